@@ -1,23 +1,39 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 use winit::dpi::{PhysicalPosition, PhysicalSize};
 use winit::event::{ElementState, Event, Ime, MouseButton, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoopBuilder, EventLoopProxy};
-use winit::keyboard::{Key, NamedKey};
+use winit::keyboard::{Key, KeyCode, NamedKey, PhysicalKey};
 use winit::window::WindowBuilder;
 
-use crate::core::{Core, CoreError, TextEncoding};
-use crate::ui::Ui;
+use crate::clipboard::Clipboard;
+use crate::core::{
+    Core, CoreError, DiffLine, Indentation, SavePoint, TextEncoding, UndoBranch, content_hash,
+    line_diff,
+};
+use crate::storage::{self, BackupConfig, BackupLocation, DiskChange, FileStamp};
+use crate::ui::{PickerView, SelectionSpan, Ui};
 
 #[derive(Debug)]
 enum AppEvent {
     BackgroundTick(u64),
+    /// The watcher saw the size or modification time of a document's file
+    /// change.
+    FileChanged {
+        doc_id: u64,
+    },
     OpenResult {
         doc_id: u64,
         request_id: u64,
         path: PathBuf,
+        /// Encoding chosen by the user, or `None` to detect it.
+        encoding: Option<TextEncoding>,
         result: Result<Vec<u8>, CoreError>,
+        undo_history: Option<Vec<u8>>,
+        stamp: Option<FileStamp>,
     },
     SaveResult {
         doc_id: u64,
@@ -25,6 +41,12 @@ enum AppEvent {
         path: PathBuf,
         encoding: TextEncoding,
         result: Result<(), CoreError>,
+        /// Set when backing up the previous version failed but the save went
+        /// ahead anyway.
+        backup_error: Option<CoreError>,
+        stamp: Option<FileStamp>,
+        /// Undo state the saved text was taken from.
+        save_point: SavePoint,
     },
 }
 
@@ -33,19 +55,169 @@ struct Document {
     core: Core,
     active_open_request: Option<u64>,
     active_save_request: Option<u64>,
+    /// The file as last opened or saved, to notice other programs changing it.
+    disk_stamp: Option<FileStamp>,
+    /// Hash of an on-disk version the user chose not to act on, so regaining
+    /// focus doesn't ask about it again.
+    dismissed_change: Option<u64>,
+    /// Append what other programs add to the file and keep the caret at the
+    /// end, like `tail -f`.
+    follow: bool,
 }
 
 impl Document {
     fn new(id: u64) -> Self {
+        let mut core = Core::new();
+        if let Some(megabytes) = std::env::var(UNDO_BUDGET_ENV)
+            .ok()
+            .and_then(|value| value.parse::<usize>().ok())
+        {
+            core.set_undo_budget(megabytes.saturating_mul(1024 * 1024));
+        }
         Self {
             id,
-            core: Core::new(),
+            core,
             active_open_request: None,
             active_save_request: None,
+            disk_stamp: None,
+            dismissed_change: None,
+            follow: false,
         }
     }
 }
 
+/// Set to `1` to keep each file's undo history across sessions.
+const PERSISTENT_UNDO_ENV: &str = "NOTEPAD_PERSISTENT_UNDO";
+
+/// Undo memory budget per document in MiB; defaults to
+/// `Core::DEFAULT_UNDO_BUDGET`.
+const UNDO_BUDGET_ENV: &str = "NOTEPAD_UNDO_BUDGET_MB";
+
+/// What the encoding picker does with the chosen encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EncodingAction {
+    /// Use it for the next save.
+    Save,
+    /// Decode the file on disk again with it.
+    Reopen,
+}
+
+/// State of the encoding picker: the labels of `TextEncoding::ALL` that
+/// contain `filter`, ignoring case, with one of them selected.
+#[derive(Debug)]
+struct EncodingPicker {
+    action: EncodingAction,
+    current: TextEncoding,
+    filter: String,
+    selected: usize,
+}
+
+impl EncodingPicker {
+    fn new(action: EncodingAction, current: TextEncoding) -> Self {
+        let selected = TextEncoding::ALL
+            .iter()
+            .position(|encoding| *encoding == current)
+            .unwrap_or(0);
+        Self {
+            action,
+            current,
+            filter: String::new(),
+            selected,
+        }
+    }
+
+    fn matches(&self) -> Vec<TextEncoding> {
+        let filter = self.filter.to_lowercase();
+        TextEncoding::ALL
+            .into_iter()
+            .filter(|encoding| encoding.label().to_lowercase().contains(&filter))
+            .collect()
+    }
+
+    fn chosen(&self) -> Option<TextEncoding> {
+        self.matches().get(self.selected).copied()
+    }
+
+    fn move_selection(&mut self, delta: isize) {
+        let last = self.matches().len().saturating_sub(1);
+        self.selected = self.selected.saturating_add_signed(delta).min(last);
+    }
+
+    fn push_filter(&mut self, text: &str) {
+        self.filter.extend(text.chars().filter(|ch| !ch.is_control()));
+        self.selected = 0;
+    }
+
+    fn pop_filter(&mut self) {
+        self.filter.pop();
+        self.selected = 0;
+    }
+
+    fn view(&self) -> PickerView {
+        let prompt = match self.action {
+            EncodingAction::Save => "Save with encoding",
+            EncodingAction::Reopen => "Reopen with encoding",
+        };
+        let items = self
+            .matches()
+            .into_iter()
+            .map(|encoding| {
+                if encoding == self.current {
+                    format!("{} (current)", encoding.label())
+                } else {
+                    encoding.label().to_string()
+                }
+            })
+            .collect();
+        PickerView {
+            title: format!("{prompt}: {}", self.filter),
+            items,
+            selected: self.selected,
+        }
+    }
+}
+
+/// Set to `1` to copy the previous version of a file next to it (`name~`)
+/// before each save.
+const BACKUP_ENV: &str = "NOTEPAD_BACKUP";
+
+/// Directory to keep backups in instead of next to each file. Setting it
+/// turns backups on.
+const BACKUP_DIR_ENV: &str = "NOTEPAD_BACKUP_DIR";
+
+/// Number of backups kept per file; defaults to 1.
+const BACKUP_GENERATIONS_ENV: &str = "NOTEPAD_BACKUP_GENERATIONS";
+
+/// Set to `1` to refuse a save whose backup failed.
+const BACKUP_STRICT_ENV: &str = "NOTEPAD_BACKUP_STRICT";
+
+/// How often the watcher thread checks the open documents' files.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+const MULTI_CLICK_INTERVAL: Duration = Duration::from_millis(500);
+const MULTI_CLICK_SLOP: f64 = 4.0;
+
+/// Counts consecutive clicks at roughly the same spot (1 = single, 2 =
+/// double, 3 = triple).
+#[derive(Debug, Default)]
+struct ClickTracker {
+    last: Option<(Instant, PhysicalPosition<f64>)>,
+    count: u32,
+}
+
+impl ClickTracker {
+    fn register(&mut self, now: Instant, position: PhysicalPosition<f64>) -> u32 {
+        let repeated = self.last.is_some_and(|(at, last_position)| {
+            now.duration_since(at) <= MULTI_CLICK_INTERVAL
+                && (position.x - last_position.x).abs() <= MULTI_CLICK_SLOP
+                && (position.y - last_position.y).abs() <= MULTI_CLICK_SLOP
+        });
+        self.count = if repeated { self.count % 3 + 1 } else { 1 };
+        self.last = Some((now, position));
+        self.count
+    }
+}
+
 pub struct App;
 
 impl App {
@@ -65,6 +237,8 @@ impl App {
         let mut documents = vec![Document::new(next_doc_id)];
         next_doc_id += 1;
         let mut active_doc_index: usize = 0;
+        let persistent_undo = std::env::var(PERSISTENT_UNDO_ENV).is_ok_and(|value| value == "1");
+        let backup = backup_config_from_env();
         refresh_ui(&mut ui, &documents, active_doc_index);
         update_title(&window, &documents[active_doc_index].core);
         update_ime_cursor_area(&window, &documents[active_doc_index].core, &ui);
@@ -80,10 +254,32 @@ impl App {
             }
         });
 
+        let watched_files: WatchList = Arc::default();
+        let watch_proxy = proxy.clone();
+        let watch_list = Arc::clone(&watched_files);
+        std::thread::spawn(move || {
+            let mut last_seen = HashMap::new();
+            loop {
+                std::thread::sleep(WATCH_INTERVAL);
+                let Ok(watched) = watch_list.lock().map(|list| list.clone()) else {
+                    break;
+                };
+                for doc_id in poll_watched_files(&watched, &mut last_seen) {
+                    if watch_proxy.send_event(AppEvent::FileChanged { doc_id }).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
         let mut needs_redraw = true;
         let mut modifiers = winit::keyboard::ModifiersState::default();
         let mut next_request_id: u64 = 1;
         let mut cursor_position: Option<PhysicalPosition<f64>> = None;
+        let mut click_tracker = ClickTracker::default();
+        let mut mouse_selecting = false;
+        let mut clipboard = Clipboard::new();
+        let mut encoding_picker: Option<EncodingPicker> = None;
 
         let result = event_loop.run(move |event, elwt| {
             elwt.set_control_flow(ControlFlow::Wait);
@@ -91,11 +287,41 @@ impl App {
                 Event::UserEvent(AppEvent::BackgroundTick(tick)) => {
                     println!("[bg] tick={tick}");
                 }
+                Event::UserEvent(AppEvent::FileChanged { doc_id }) => {
+                    let active_doc_id = documents
+                        .get(active_doc_index)
+                        .map(|doc| doc.id)
+                        .unwrap_or_default();
+                    let Some(doc) = documents.iter_mut().find(|doc| doc.id == doc_id) else {
+                        return;
+                    };
+                    if doc.active_open_request.is_some() || doc.active_save_request.is_some() {
+                        return;
+                    }
+                    let appended = doc.follow && append_followed_file(doc);
+                    // Dirty documents are left to the checks on focus and save,
+                    // which ask before anything is lost.
+                    if !appended && !doc.core.is_dirty() && check_external_change(doc) {
+                        let request_id = next_request_id;
+                        next_request_id += 1;
+                        reload_document(doc, &proxy, request_id, persistent_undo);
+                    }
+                    if appended && doc_id == active_doc_id {
+                        refresh_ui(&mut ui, &documents, active_doc_index);
+                        let doc = &documents[active_doc_index];
+                        update_title(&window, &doc.core);
+                        update_ime_cursor_area(&window, &doc.core, &ui);
+                        needs_redraw = true;
+                    }
+                }
                 Event::UserEvent(AppEvent::OpenResult {
                     doc_id,
                     request_id,
                     path,
+                    encoding,
                     result,
+                    undo_history,
+                    stamp,
                 }) => {
                     let active_doc_id = documents
                         .get(active_doc_index)
@@ -111,17 +337,29 @@ impl App {
                     }
                     doc.active_open_request = None;
                     match result {
-                        Ok(bytes) => match doc.core.load_from_bytes(&bytes) {
-                            Ok(_) => {
-                                doc.core.set_path(Some(path));
-                                if active_doc_id == doc_id {
-                                    refresh_active = true;
-                                } else {
-                                    refresh_only_tabs = true;
-                                }
+                        Ok(bytes) => {
+                            match encoding {
+                                Some(encoding) => doc.core.load_from_bytes_as(&bytes, encoding),
+                                None => doc.core.load_from_bytes(&bytes),
+                            };
+                            doc.disk_stamp = stamp;
+                            doc.dismissed_change = None;
+                            if doc.follow {
+                                doc.core.move_document_end(false);
+                            }
+                            if let Some(err) = doc.core.decode_error() {
+                                report_error(&err);
                             }
-                            Err(err) => report_error(&err),
-                        },
+                            if let Some(history) = undo_history {
+                                doc.core.restore_undo_history(&history);
+                            }
+                            doc.core.set_path(Some(path));
+                            if active_doc_id == doc_id {
+                                refresh_active = true;
+                            } else {
+                                refresh_only_tabs = true;
+                            }
+                        }
                         Err(err) => report_error(&err),
                     }
                     if refresh_active {
@@ -134,6 +372,7 @@ impl App {
                         refresh_tabs(&mut ui, &documents, active_doc_index);
                         needs_redraw = true;
                     }
+                    sync_watched_files(&watched_files, &documents);
                 }
                 Event::UserEvent(AppEvent::SaveResult {
                     doc_id,
@@ -141,6 +380,9 @@ impl App {
                     path,
                     encoding,
                     result,
+                    backup_error,
+                    stamp,
+                    save_point,
                 }) => {
                     let active_doc_id = documents
                         .get(active_doc_index)
@@ -155,9 +397,23 @@ impl App {
                         return;
                     }
                     doc.active_save_request = None;
+                    if let Some(err) = backup_error {
+                        report_error(&err);
+                    }
                     match result {
                         Ok(()) => {
-                            doc.core.mark_saved(path, encoding);
+                            doc.core.mark_saved_at(path, encoding, save_point);
+                            doc.disk_stamp = stamp;
+                            doc.dismissed_change = None;
+                            if persistent_undo
+                                && let Some(store_path) =
+                                    doc.core.path().and_then(undo_store_path)
+                                && let Some(history) = doc.core.undo_history_bytes()
+                            {
+                                std::thread::spawn(move || {
+                                    write_undo_history(&store_path, &history)
+                                });
+                            }
                             if active_doc_id == doc_id {
                                 refresh_title = true;
                             }
@@ -173,10 +429,18 @@ impl App {
                         refresh_tabs(&mut ui, &documents, active_doc_index);
                         needs_redraw = true;
                     }
+                    sync_watched_files(&watched_files, &documents);
                 }
                 Event::WindowEvent { event, window_id } if window_id == window.id() => {
                     match event {
-                        WindowEvent::CloseRequested => elwt.exit(),
+                        WindowEvent::CloseRequested => {
+                            if persistent_undo {
+                                for doc in &documents {
+                                    persist_undo_history(&doc.core);
+                                }
+                            }
+                            elwt.exit();
+                        }
                         WindowEvent::Resized(size) => {
                             ui.resize(size);
                             needs_redraw = true;
@@ -190,43 +454,102 @@ impl App {
                             ui.resize(size);
                             needs_redraw = true;
                         }
+                        WindowEvent::Focused(focused) => {
+                            ui.set_focused(focused);
+                            needs_redraw = true;
+                            if focused {
+                                for doc in documents.iter_mut() {
+                                    if check_external_change(doc) {
+                                        let request_id = next_request_id;
+                                        next_request_id += 1;
+                                        reload_document(
+                                            doc,
+                                            &proxy,
+                                            request_id,
+                                            persistent_undo,
+                                        );
+                                    }
+                                }
+                            }
+                        }
                         WindowEvent::ModifiersChanged(state) => {
                             modifiers = state.state();
                         }
                         WindowEvent::CursorMoved { position, .. } => {
                             cursor_position = Some(position);
+                            if mouse_selecting {
+                                let changed = {
+                                    let core = &mut documents[active_doc_index].core;
+                                    let (line, col) =
+                                        ui.text_drag_position(position, core.line_count());
+                                    core.set_cursor_line_col(line, col, true)
+                                };
+                                if changed {
+                                    refresh_ui(&mut ui, &documents, active_doc_index);
+                                    let doc = &documents[active_doc_index];
+                                    update_title(&window, &doc.core);
+                                    update_ime_cursor_area(&window, &doc.core, &ui);
+                                    needs_redraw = true;
+                                }
+                            }
                         }
-                        WindowEvent::MouseInput { state, button, .. } => {
-                            if state == ElementState::Pressed && button == MouseButton::Left {
-                                if let Some(position) = cursor_position {
-                                    let line_count =
-                                        documents[active_doc_index].core.line_count();
-                                    if let Some(line) =
-                                        ui.line_number_hit_test(position, line_count)
+                        WindowEvent::MouseInput {
+                            state: ElementState::Released,
+                            button: MouseButton::Left,
+                            ..
+                        } => {
+                            mouse_selecting = false;
+                        }
+                        WindowEvent::MouseInput {
+                            state: ElementState::Pressed,
+                            button: MouseButton::Left,
+                            ..
+                        } => {
+                            if let Some(position) = cursor_position {
+                                let line_count = documents[active_doc_index].core.line_count();
+                                let mut changed = false;
+                                if let Some(line) = ui.line_number_hit_test(position, line_count) {
+                                    let doc = &mut documents[active_doc_index];
+                                    changed = doc.core.set_cursor_line_col(line, 0, false);
+                                } else if let Some((line, col)) =
+                                    ui.text_hit_test(position, line_count)
+                                {
+                                    let core = &mut documents[active_doc_index].core;
+                                    changed = match click_tracker.register(Instant::now(), position)
                                     {
-                                        let changed = {
-                                            let doc = &mut documents[active_doc_index];
-                                            doc.core.set_cursor_line_col(line, 0, false)
-                                        };
-                                        if changed {
-                                            refresh_ui(
-                                                &mut ui,
-                                                &documents,
-                                                active_doc_index,
-                                            );
-                                            let doc = &documents[active_doc_index];
-                                            update_title(&window, &doc.core);
-                                            update_ime_cursor_area(
-                                                &window,
-                                                &doc.core,
-                                                &ui,
-                                            );
-                                            needs_redraw = true;
+                                        2 => core.select_word_at(line, col),
+                                        3 => core.select_line(line),
+                                        _ => {
+                                            mouse_selecting = true;
+                                            core.set_cursor_line_col(
+                                                line,
+                                                col,
+                                                modifiers.shift_key(),
+                                            )
                                         }
-                                    }
+                                    };
+                                }
+                                if changed {
+                                    refresh_ui(&mut ui, &documents, active_doc_index);
+                                    let doc = &documents[active_doc_index];
+                                    update_title(&window, &doc.core);
+                                    update_ime_cursor_area(&window, &doc.core, &ui);
+                                    needs_redraw = true;
                                 }
                             }
                         }
+                        WindowEvent::MouseWheel { delta, .. } => {
+                            let line_count = documents[active_doc_index].core.line_count();
+                            if ui.scroll_by(delta, line_count) {
+                                refresh_selection(&mut ui, &documents[active_doc_index].core);
+                                update_ime_cursor_area(
+                                    &window,
+                                    &documents[active_doc_index].core,
+                                    &ui,
+                                );
+                                needs_redraw = true;
+                            }
+                        }
                         WindowEvent::Ime(ime) => {
                             log_ime_event(&ime);
                             {
@@ -252,160 +575,293 @@ impl App {
                             update_ime_cursor_area(&window, &doc.core, &ui);
                             needs_redraw = true;
                         }
-                        WindowEvent::KeyboardInput { event, .. } => {
-                            if event.state == ElementState::Pressed {
-                                let mut changed = false;
-                                let command_key =
-                                    modifiers.super_key() || modifiers.control_key();
-                                let doc_id = documents[active_doc_index].id;
-                                match event.logical_key {
-                                    Key::Character(ref ch)
-                                        if command_key && ch.eq_ignore_ascii_case("o") =>
+                        WindowEvent::KeyboardInput { event, .. }
+                            if event.state == ElementState::Pressed
+                                && encoding_picker.is_some() =>
+                        {
+                            let Some(picker) = encoding_picker.as_mut() else {
+                                return;
+                            };
+                            let mut chosen = None;
+                            let mut close = false;
+                            match event.logical_key {
+                                Key::Named(NamedKey::Escape) => close = true,
+                                Key::Named(NamedKey::Enter) => {
+                                    chosen = picker.chosen();
+                                    close = true;
+                                }
+                                Key::Named(NamedKey::ArrowUp) => picker.move_selection(-1),
+                                Key::Named(NamedKey::ArrowDown) => picker.move_selection(1),
+                                Key::Named(NamedKey::Backspace) => picker.pop_filter(),
+                                _ => {
+                                    if let Some(text) = event.text.as_deref()
+                                        && !modifiers.super_key()
+                                        && !modifiers.control_key()
                                     {
-                                        if let Some(path) = pick_open_path() {
-                                            let request_id = next_request_id;
-                                            next_request_id += 1;
-                                            documents[active_doc_index].active_open_request =
-                                                Some(request_id);
-                                            start_open_task(
-                                                proxy.clone(),
-                                                doc_id,
-                                                request_id,
-                                                path,
-                                            );
-                                        }
+                                        picker.push_filter(text);
                                     }
-                                    Key::Character(ref ch)
-                                        if command_key && ch.eq_ignore_ascii_case("s") =>
+                                }
+                            }
+                            let action = picker.action;
+                            if close {
+                                encoding_picker = None;
+                                ui.set_picker(None);
+                            } else {
+                                ui.set_picker(Some(picker.view()));
+                            }
+                            needs_redraw = true;
+
+                            let doc = &mut documents[active_doc_index];
+                            match (chosen, action) {
+                                (Some(encoding), EncodingAction::Save) => {
+                                    doc.core.set_encoding(encoding);
+                                    // Refreshes the title, tabs and unencodable highlights.
+                                    refresh_ui(&mut ui, &documents, active_doc_index);
+                                    update_title(&window, &documents[active_doc_index].core);
+                                }
+                                (Some(encoding), EncodingAction::Reopen) => {
+                                    if let Some(path) = doc.core.path().map(PathBuf::from)
+                                        && confirm_discard_changes(
+                                            &doc.core,
+                                            &format!("Reopen with {}", encoding.label()),
+                                        )
                                     {
-                                        if modifiers.shift_key() {
-                                            if let Some(path) = pick_save_path(
-                                                documents[active_doc_index].core.path(),
-                                            ) {
-                                                let request_id = next_request_id;
-                                                next_request_id += 1;
-                                                documents[active_doc_index].active_save_request =
-                                                    Some(request_id);
-                                                start_save_task(
-                                                    proxy.clone(),
-                                                    doc_id,
-                                                    request_id,
-                                                    path,
-                                                    documents[active_doc_index].core.encoding(),
-                                                    documents[active_doc_index].core.text(),
-                                                );
-                                            }
-                                        } else if let Some(path) = documents[active_doc_index]
-                                            .core
+                                        let request_id = next_request_id;
+                                        next_request_id += 1;
+                                        doc.active_open_request = Some(request_id);
+                                        start_open_task(
+                                            proxy.clone(),
+                                            doc.id,
+                                            request_id,
+                                            path,
+                                            Some(encoding),
+                                            persistent_undo,
+                                        );
+                                    }
+                                }
+                                (None, _) => {}
+                            }
+                        }
+                        WindowEvent::KeyboardInput { event, .. }
+                            if event.state == ElementState::Pressed =>
+                        {
+                            let mut changed = false;
+                            let command_key =
+                                modifiers.super_key() || modifiers.control_key();
+                            let doc_id = documents[active_doc_index].id;
+                            match event.logical_key {
+                                _ if command_key
+                                    && modifiers.alt_key()
+                                    && event.physical_key == PhysicalKey::Code(KeyCode::KeyT) =>
+                                {
+                                    let doc = &mut documents[active_doc_index];
+                                    let indentation = doc.core.indentation();
+                                    doc.core.set_indentation(Indentation {
+                                        soft_tabs: !indentation.soft_tabs,
+                                        ..indentation
+                                    });
+                                    update_title(&window, &doc.core);
+                                }
+                                _ if command_key
+                                    && modifiers.alt_key()
+                                    && event.physical_key == PhysicalKey::Code(KeyCode::KeyW) =>
+                                {
+                                    let doc = &mut documents[active_doc_index];
+                                    let indentation = doc.core.indentation();
+                                    doc.core.set_indentation(Indentation {
+                                        tab_width: indentation.next_tab_width(),
+                                        ..indentation
+                                    });
+                                    changed = true;
+                                }
+                                _ if command_key
+                                    && modifiers.alt_key()
+                                    && event.physical_key == PhysicalKey::Code(KeyCode::KeyL) =>
+                                {
+                                    let core = &mut documents[active_doc_index].core;
+                                    core.set_line_ending(core.line_ending().next());
+                                    changed = true;
+                                }
+                                _ if command_key
+                                    && modifiers.alt_key()
+                                    && event.physical_key == PhysicalKey::Code(KeyCode::KeyF) =>
+                                {
+                                    let doc = &mut documents[active_doc_index];
+                                    if doc.core.path().is_some() {
+                                        doc.follow = !doc.follow;
+                                        if doc.follow {
+                                            doc.core.move_document_end(false);
+                                        }
+                                        changed = true;
+                                    }
+                                }
+                                Key::Character(ref ch)
+                                    if command_key && ch.eq_ignore_ascii_case("o") =>
+                                {
+                                    if let Some(path) = pick_open_path() {
+                                        let request_id = next_request_id;
+                                        next_request_id += 1;
+                                        documents[active_doc_index].active_open_request =
+                                            Some(request_id);
+                                        start_open_task(
+                                            proxy.clone(),
+                                            doc_id,
+                                            request_id,
+                                            path,
+                                            None,
+                                            persistent_undo,
+                                        );
+                                    }
+                                }
+                                Key::Character(ref ch)
+                                    if command_key && ch.eq_ignore_ascii_case("s") =>
+                                {
+                                    let doc = &mut documents[active_doc_index];
+                                    let path = if modifiers.shift_key() {
+                                        pick_save_path(doc.core.path())
+                                    } else {
+                                        doc.core
                                             .path()
                                             .map(PathBuf::from)
-                                        {
-                                            let request_id = next_request_id;
-                                            next_request_id += 1;
-                                            documents[active_doc_index].active_save_request =
-                                                Some(request_id);
-                                            start_save_task(
-                                                proxy.clone(),
-                                                doc_id,
-                                                request_id,
-                                                path,
-                                                documents[active_doc_index].core.encoding(),
-                                                documents[active_doc_index].core.text(),
-                                            );
-                                        } else if let Some(path) = pick_save_path(
-                                            documents[active_doc_index].core.path(),
-                                        ) {
+                                            .or_else(|| pick_save_path(None))
+                                    };
+                                    let conflict = path.as_deref().map_or(
+                                        ConflictChoice::Overwrite,
+                                        |path| check_before_save(doc, path),
+                                    );
+                                    if conflict == ConflictChoice::Reload {
+                                        let request_id = next_request_id;
+                                        next_request_id += 1;
+                                        reload_document(
+                                            doc,
+                                            &proxy,
+                                            request_id,
+                                            persistent_undo,
+                                        );
+                                    } else if let Some(path) = path
+                                        && conflict == ConflictChoice::Overwrite
+                                    {
+                                        if confirm_lossy_save(&mut doc.core) {
                                             let request_id = next_request_id;
                                             next_request_id += 1;
-                                            documents[active_doc_index].active_save_request =
-                                                Some(request_id);
+                                            doc.active_save_request = Some(request_id);
                                             start_save_task(
                                                 proxy.clone(),
                                                 doc_id,
                                                 request_id,
                                                 path,
-                                                documents[active_doc_index].core.encoding(),
-                                                documents[active_doc_index].core.text(),
+                                                &mut doc.core,
+                                                backup.clone(),
                                             );
+                                        } else {
+                                            changed = true;
                                         }
                                     }
-                                    Key::Character(ref ch)
-                                        if command_key && ch.eq_ignore_ascii_case("t") =>
-                                    {
-                                        let new_doc = Document::new(next_doc_id);
-                                        next_doc_id += 1;
-                                        documents.push(new_doc);
-                                        let last_index = documents.len() - 1;
-                                        switch_to_tab(
-                                            &mut documents,
-                                            &mut active_doc_index,
-                                            last_index,
-                                        );
-                                        refresh_ui(&mut ui, &documents, active_doc_index);
-                                        update_title(
-                                            &window,
-                                            &documents[active_doc_index].core,
-                                        );
-                                        update_ime_cursor_area(
-                                            &window,
-                                            &documents[active_doc_index].core,
-                                            &ui,
-                                        );
-                                        needs_redraw = true;
-                                    }
-                                    Key::Character(ref ch)
-                                        if command_key && ch.eq_ignore_ascii_case("w") =>
-                                    {
-                                        close_current_tab(
-                                            &mut documents,
-                                            &mut active_doc_index,
-                                        );
-                                        refresh_ui(&mut ui, &documents, active_doc_index);
-                                        update_title(
-                                            &window,
-                                            &documents[active_doc_index].core,
-                                        );
-                                        update_ime_cursor_area(
-                                            &window,
-                                            &documents[active_doc_index].core,
-                                            &ui,
-                                        );
-                                        needs_redraw = true;
-                                    }
-                                    Key::Character(ref ch)
-                                        if command_key && modifiers.shift_key() && ch == "[" =>
-                                    {
-                                        let next_index = if active_doc_index == 0 {
-                                            documents.len().saturating_sub(1)
-                                        } else {
-                                            active_doc_index - 1
-                                        };
-                                        switch_to_tab(
-                                            &mut documents,
-                                            &mut active_doc_index,
-                                            next_index,
-                                        );
-                                        refresh_ui(&mut ui, &documents, active_doc_index);
-                                        update_title(
-                                            &window,
-                                            &documents[active_doc_index].core,
-                                        );
-                                        update_ime_cursor_area(
-                                            &window,
-                                            &documents[active_doc_index].core,
-                                            &ui,
-                                        );
-                                        needs_redraw = true;
+                                }
+                                Key::Character(ref ch)
+                                    if command_key && ch.eq_ignore_ascii_case("t") =>
+                                {
+                                    let new_doc = Document::new(next_doc_id);
+                                    next_doc_id += 1;
+                                    documents.push(new_doc);
+                                    let last_index = documents.len() - 1;
+                                    switch_to_tab(
+                                        &mut documents,
+                                        &mut active_doc_index,
+                                        last_index,
+                                    );
+                                    refresh_ui(&mut ui, &documents, active_doc_index);
+                                    update_title(
+                                        &window,
+                                        &documents[active_doc_index].core,
+                                    );
+                                    update_ime_cursor_area(
+                                        &window,
+                                        &documents[active_doc_index].core,
+                                        &ui,
+                                    );
+                                    needs_redraw = true;
+                                }
+                                Key::Character(ref ch)
+                                    if command_key && ch.eq_ignore_ascii_case("w") =>
+                                {
+                                    if persistent_undo {
+                                        persist_undo_history(&documents[active_doc_index].core);
                                     }
-                                    Key::Character(ref ch)
-                                        if command_key && modifiers.shift_key() && ch == "]" =>
+                                    close_current_tab(
+                                        &mut documents,
+                                        &mut active_doc_index,
+                                    );
+                                    sync_watched_files(&watched_files, &documents);
+                                    refresh_ui(&mut ui, &documents, active_doc_index);
+                                    update_title(
+                                        &window,
+                                        &documents[active_doc_index].core,
+                                    );
+                                    update_ime_cursor_area(
+                                        &window,
+                                        &documents[active_doc_index].core,
+                                        &ui,
+                                    );
+                                    needs_redraw = true;
+                                }
+                                Key::Character(ref ch)
+                                    if command_key && modifiers.shift_key() && ch == "[" =>
+                                {
+                                    let next_index = if active_doc_index == 0 {
+                                        documents.len().saturating_sub(1)
+                                    } else {
+                                        active_doc_index - 1
+                                    };
+                                    switch_to_tab(
+                                        &mut documents,
+                                        &mut active_doc_index,
+                                        next_index,
+                                    );
+                                    refresh_ui(&mut ui, &documents, active_doc_index);
+                                    update_title(
+                                        &window,
+                                        &documents[active_doc_index].core,
+                                    );
+                                    update_ime_cursor_area(
+                                        &window,
+                                        &documents[active_doc_index].core,
+                                        &ui,
+                                    );
+                                    needs_redraw = true;
+                                }
+                                Key::Character(ref ch)
+                                    if command_key && modifiers.shift_key() && ch == "]" =>
+                                {
+                                    let next_index =
+                                        (active_doc_index + 1) % documents.len();
+                                    switch_to_tab(
+                                        &mut documents,
+                                        &mut active_doc_index,
+                                        next_index,
+                                    );
+                                    refresh_ui(&mut ui, &documents, active_doc_index);
+                                    update_title(
+                                        &window,
+                                        &documents[active_doc_index].core,
+                                    );
+                                    update_ime_cursor_area(
+                                        &window,
+                                        &documents[active_doc_index].core,
+                                        &ui,
+                                    );
+                                    needs_redraw = true;
+                                }
+                                Key::Character(ref ch)
+                                    if command_key && is_tab_index_key(ch) =>
+                                {
+                                    if let Some(index) = tab_index_from_key(ch)
+                                        && index < documents.len()
                                     {
-                                        let next_index =
-                                            (active_doc_index + 1) % documents.len();
                                         switch_to_tab(
                                             &mut documents,
                                             &mut active_doc_index,
-                                            next_index,
+                                            index,
                                         );
                                         refresh_ui(&mut ui, &documents, active_doc_index);
                                         update_title(
@@ -419,149 +875,171 @@ impl App {
                                         );
                                         needs_redraw = true;
                                     }
-                                    Key::Character(ref ch)
-                                        if command_key && is_tab_index_key(ch) =>
-                                    {
-                                        if let Some(index) = tab_index_from_key(ch) {
-                                            if index < documents.len() {
-                                                switch_to_tab(
-                                                    &mut documents,
-                                                    &mut active_doc_index,
-                                                    index,
-                                                );
-                                                refresh_ui(
-                                                    &mut ui,
-                                                    &documents,
-                                                    active_doc_index,
-                                                );
-                                                update_title(
-                                                    &window,
-                                                    &documents[active_doc_index].core,
-                                                );
-                                                update_ime_cursor_area(
-                                                    &window,
-                                                    &documents[active_doc_index].core,
-                                                    &ui,
-                                                );
-                                                needs_redraw = true;
-                                            }
-                                        }
-                                    }
-                                    Key::Character(ref ch)
-                                        if command_key && ch.eq_ignore_ascii_case("z") =>
-                                    {
-                                        let doc = &mut documents[active_doc_index];
-                                        if modifiers.shift_key() {
-                                            changed = doc.core.redo();
-                                        } else {
-                                            changed = doc.core.undo();
-                                        }
-                                    }
-                                    Key::Character(ref ch)
-                                        if command_key && ch.eq_ignore_ascii_case("y") =>
-                                    {
-                                        let doc = &mut documents[active_doc_index];
-                                        changed = doc.core.redo();
-                                    }
-                                    Key::Character(ref ch)
-                                        if command_key && modifiers.shift_key()
-                                            && ch.eq_ignore_ascii_case("e") =>
-                                    {
-                                        let doc = &mut documents[active_doc_index];
-                                        doc.core.set_encoding(doc.core.encoding().next());
-                                        update_title(&window, &doc.core);
-                                        refresh_tabs(&mut ui, &documents, active_doc_index);
-                                    }
-                                    Key::Character(ref ch)
-                                        if command_key && ch == "1" =>
-                                    {
-                                        let doc = &mut documents[active_doc_index];
-                                        doc.core.set_encoding(TextEncoding::Utf8);
-                                        update_title(&window, &doc.core);
-                                        refresh_tabs(&mut ui, &documents, active_doc_index);
+                                }
+                                Key::Character(ref ch)
+                                    if command_key && ch.eq_ignore_ascii_case("c") =>
+                                {
+                                    let core = &documents[active_doc_index].core;
+                                    if let Some(text) = core.selected_text() {
+                                        clipboard.set_text(text);
                                     }
-                                    Key::Character(ref ch)
-                                        if command_key && ch == "2" =>
-                                    {
-                                        let doc = &mut documents[active_doc_index];
-                                        doc.core.set_encoding(TextEncoding::Utf16Le);
-                                        update_title(&window, &doc.core);
-                                        refresh_tabs(&mut ui, &documents, active_doc_index);
+                                }
+                                Key::Character(ref ch)
+                                    if command_key && ch.eq_ignore_ascii_case("x") =>
+                                {
+                                    if let Some(text) = documents[active_doc_index].core.cut() {
+                                        clipboard.set_text(text);
+                                        changed = true;
                                     }
-                                    Key::Character(ref ch)
-                                        if command_key && ch == "3" =>
+                                }
+                                Key::Character(ref ch)
+                                    if command_key && ch.eq_ignore_ascii_case("v") =>
+                                {
+                                    if let Some(text) = clipboard.get_text()
+                                        && !text.is_empty()
                                     {
-                                        let doc = &mut documents[active_doc_index];
-                                        doc.core.set_encoding(TextEncoding::Utf16Be);
-                                        update_title(&window, &doc.core);
-                                        refresh_tabs(&mut ui, &documents, active_doc_index);
+                                        let core = &mut documents[active_doc_index].core;
+                                        core.begin_group();
+                                        core.insert_str(&text);
+                                        core.end_group();
+                                        changed = true;
                                     }
-                                    Key::Character(ref ch)
-                                        if command_key && ch == "4" =>
-                                    {
-                                        let doc = &mut documents[active_doc_index];
-                                        doc.core.set_encoding(TextEncoding::ShiftJis);
-                                        update_title(&window, &doc.core);
-                                        refresh_tabs(&mut ui, &documents, active_doc_index);
+                                }
+                                Key::Character(ref ch)
+                                    if modifiers.control_key() && ch.eq_ignore_ascii_case("k") =>
+                                {
+                                    documents[active_doc_index].core.kill_to_line_end();
+                                    changed = true;
+                                }
+                                _ if command_key
+                                    && modifiers.alt_key()
+                                    && event.physical_key == PhysicalKey::Code(KeyCode::KeyZ) =>
+                                {
+                                    let core = &mut documents[active_doc_index].core;
+                                    changed = if modifiers.shift_key() {
+                                        core.undo_later()
+                                    } else {
+                                        core.undo_earlier()
+                                    };
+                                }
+                                _ if command_key
+                                    && modifiers.alt_key()
+                                    && event.physical_key == PhysicalKey::Code(KeyCode::KeyU) =>
+                                {
+                                    let core = &mut documents[active_doc_index].core;
+                                    if let Some(target) = pick_undo_branch(core) {
+                                        changed = core.goto_undo_state(target);
                                     }
-                                    Key::Named(NamedKey::Backspace) => {
-                                        documents[active_doc_index].core.backspace();
-                                        changed = true;
+                                }
+                                Key::Character(ref ch)
+                                    if command_key && ch.eq_ignore_ascii_case("z") =>
+                                {
+                                    let doc = &mut documents[active_doc_index];
+                                    if modifiers.shift_key() {
+                                        changed = doc.core.redo();
+                                    } else {
+                                        changed = doc.core.undo();
                                     }
-                                    Key::Named(NamedKey::ArrowLeft) => {
-                                        changed = move_cursor(
-                                            &mut documents[active_doc_index].core,
-                                            Direction::Left,
-                                            modifiers.shift_key(),
+                                }
+                                Key::Character(ref ch)
+                                    if command_key && ch.eq_ignore_ascii_case("y") =>
+                                {
+                                    let doc = &mut documents[active_doc_index];
+                                    changed = doc.core.redo();
+                                }
+                                _ if command_key
+                                    && modifiers.alt_key()
+                                    && event.physical_key == PhysicalKey::Code(KeyCode::KeyE) =>
+                                {
+                                    let core = &documents[active_doc_index].core;
+                                    if core.path().is_some() {
+                                        let picker = EncodingPicker::new(
+                                            EncodingAction::Reopen,
+                                            core.encoding(),
                                         );
+                                        ui.set_picker(Some(picker.view()));
+                                        encoding_picker = Some(picker);
+                                        needs_redraw = true;
                                     }
-                                    Key::Named(NamedKey::ArrowRight) => {
-                                        changed = move_cursor(
-                                            &mut documents[active_doc_index].core,
-                                            Direction::Right,
-                                            modifiers.shift_key(),
-                                        );
+                                }
+                                Key::Character(ref ch)
+                                    if command_key && modifiers.shift_key()
+                                        && ch.eq_ignore_ascii_case("e") =>
+                                {
+                                    let core = &documents[active_doc_index].core;
+                                    let picker =
+                                        EncodingPicker::new(EncodingAction::Save, core.encoding());
+                                    ui.set_picker(Some(picker.view()));
+                                    encoding_picker = Some(picker);
+                                    needs_redraw = true;
+                                }
+                                Key::Named(NamedKey::Backspace) => {
+                                    let core = &mut documents[active_doc_index].core;
+                                    if modifiers.super_key() {
+                                        core.delete_to_line_start();
+                                    } else if modifiers.alt_key() {
+                                        core.delete_word_backward();
+                                    } else {
+                                        core.backspace();
                                     }
-                                    Key::Named(NamedKey::ArrowUp) => {
-                                        changed = move_cursor(
-                                            &mut documents[active_doc_index].core,
-                                            Direction::Up,
-                                            modifiers.shift_key(),
-                                        );
+                                    changed = true;
+                                }
+                                Key::Named(NamedKey::Delete) => {
+                                    documents[active_doc_index].core.delete_forward();
+                                    changed = true;
+                                }
+                                Key::Named(NamedKey::Tab) if !command_key => {
+                                    let core = &mut documents[active_doc_index].core;
+                                    if modifiers.shift_key() {
+                                        core.indent_lines(true);
+                                    } else {
+                                        core.insert_tab();
                                     }
-                                    Key::Named(NamedKey::ArrowDown) => {
+                                    changed = true;
+                                }
+                                Key::Named(
+                                    named @ (NamedKey::ArrowLeft
+                                    | NamedKey::ArrowRight
+                                    | NamedKey::ArrowUp
+                                    | NamedKey::ArrowDown
+                                    | NamedKey::Home
+                                    | NamedKey::End
+                                    | NamedKey::PageUp
+                                    | NamedKey::PageDown),
+                                ) => {
+                                    if let Some(direction) =
+                                        direction_for_key(named, modifiers, ui.visible_line_count())
+                                    {
                                         changed = move_cursor(
                                             &mut documents[active_doc_index].core,
-                                            Direction::Down,
+                                            direction,
                                             modifiers.shift_key(),
                                         );
                                     }
-                                    Key::Named(NamedKey::Enter) => {
-                                        documents[active_doc_index].core.insert_str("\n");
-                                        changed = true;
-                                    }
-                                    _ => {}
                                 }
-
-                                if !changed {
-                                    if let Some(text) = event.text.as_ref() {
-                                        if !modifiers.control_key()
-                                            && !modifiers.alt_key()
-                                            && !modifiers.super_key()
-                                        {
-                                            documents[active_doc_index].core.insert_str(text);
-                                            changed = true;
-                                        }
-                                    }
+                                Key::Named(NamedKey::Enter) => {
+                                    documents[active_doc_index].core.insert_str("\n");
+                                    changed = true;
                                 }
+                                _ => {}
+                            }
 
-                                if changed {
-                                    refresh_ui(&mut ui, &documents, active_doc_index);
-                                    let doc = &documents[active_doc_index];
-                                    update_title(&window, &doc.core);
-                                    update_ime_cursor_area(&window, &doc.core, &ui);
-                                    needs_redraw = true;
-                                }
+                            if !changed
+                                && let Some(text) = event.text.as_ref()
+                                && !modifiers.control_key()
+                                && !modifiers.alt_key()
+                                && !modifiers.super_key()
+                            {
+                                documents[active_doc_index].core.insert_str(text);
+                                changed = true;
+                            }
+
+                            if changed {
+                                refresh_ui(&mut ui, &documents, active_doc_index);
+                                let doc = &documents[active_doc_index];
+                                update_title(&window, &doc.core);
+                                update_ime_cursor_area(&window, &doc.core, &ui);
+                                needs_redraw = true;
                             }
                         }
                         WindowEvent::RedrawRequested => {
@@ -577,11 +1055,9 @@ impl App {
                         _ => {}
                     }
                 }
-                Event::AboutToWait => {
-                    if needs_redraw {
-                        window.request_redraw();
-                        needs_redraw = false;
-                    }
+                Event::AboutToWait if needs_redraw => {
+                    window.request_redraw();
+                    needs_redraw = false;
                 }
                 _ => {}
             }
@@ -596,6 +1072,271 @@ fn pick_open_path() -> Option<PathBuf> {
     rfd::FileDialog::new().pick_file()
 }
 
+/// Checks that the document can be saved in its encoding without loss. If
+/// not, reports the problem and asks whether to save anyway; on refusal the
+/// caret moves to the first character that cannot be encoded.
+fn confirm_lossy_save(core: &mut Core) -> bool {
+    let encoding = core.encoding();
+    let Err(err) = core.check_encodable(encoding) else {
+        return true;
+    };
+    report_error(&err);
+    let first = err.positions().first().copied();
+    let confirmed = rfd::MessageDialog::new()
+        .set_level(rfd::MessageLevel::Warning)
+        .set_title("Save with Lossy Encoding")
+        .set_description(format!(
+            "Some characters cannot be saved as {}. They are highlighted in the \
+             document and will be written as numeric character references \
+             (like &#128512;). Save anyway?",
+            encoding.label()
+        ))
+        .set_buttons(rfd::MessageButtons::YesNo)
+        .show()
+        == rfd::MessageDialogResult::Yes;
+    if !confirmed && let Some(first) = first {
+        let cursor = core.cursor_for_char(first);
+        core.set_cursor_line_col(cursor.line, cursor.col, false);
+    }
+    confirmed
+}
+
+/// Asks before an action that would throw away unsaved edits. Returns `true`
+/// if there is nothing to lose or the user agrees.
+fn confirm_discard_changes(core: &Core, action: &str) -> bool {
+    if !core.is_dirty() {
+        return true;
+    }
+    let name = core
+        .path()
+        .and_then(|path| path.file_name())
+        .and_then(|name| name.to_str())
+        .unwrap_or("Untitled");
+    rfd::MessageDialog::new()
+        .set_level(rfd::MessageLevel::Warning)
+        .set_title(action)
+        .set_description(format!(
+            "\"{name}\" has unsaved changes that will be lost. {action} anyway?"
+        ))
+        .set_buttons(rfd::MessageButtons::YesNo)
+        .show()
+        == rfd::MessageDialogResult::Yes
+}
+
+/// How to settle a file that another program changed while it was open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConflictChoice {
+    /// Discard the document's version and load the one on disk.
+    Reload,
+    /// Keep the document's version; the next save replaces the one on disk.
+    Overwrite,
+    /// Decide later.
+    Cancel,
+}
+
+/// Checks a document's file when the window regains focus. Returns whether
+/// it should be reloaded: always for a clean document, and for a dirty one
+/// only if the user says so.
+fn check_external_change(doc: &mut Document) -> bool {
+    if doc.active_open_request.is_some() || doc.active_save_request.is_some() {
+        return false;
+    }
+    let (Some(path), Some(stamp)) = (doc.core.path(), doc.disk_stamp.as_mut()) else {
+        return false;
+    };
+    let (current, bytes) = match storage::check_disk(path, stamp) {
+        Ok(DiskChange::Modified { stamp, bytes }) => (stamp, bytes),
+        Ok(DiskChange::Unchanged | DiskChange::Missing) => return false,
+        Err(err) => {
+            report_error(&err);
+            return false;
+        }
+    };
+    if !doc.core.is_dirty() {
+        return true;
+    }
+    if doc.dismissed_change == Some(current.hash) {
+        return false;
+    }
+    match resolve_disk_conflict(&doc.core, &bytes) {
+        ConflictChoice::Reload => true,
+        ConflictChoice::Overwrite => {
+            doc.disk_stamp = Some(current);
+            false
+        }
+        ConflictChoice::Cancel => {
+            doc.dismissed_change = Some(current.hash);
+            false
+        }
+    }
+}
+
+/// Checks that saving to `path` won't silently replace changes another
+/// program made to the document's file since it was opened or saved.
+fn check_before_save(doc: &mut Document, path: &std::path::Path) -> ConflictChoice {
+    if doc.core.path() != Some(path) {
+        return ConflictChoice::Overwrite;
+    }
+    let Some(stamp) = doc.disk_stamp.as_mut() else {
+        return ConflictChoice::Overwrite;
+    };
+    match storage::check_disk(path, stamp) {
+        Ok(DiskChange::Modified { bytes, .. }) => resolve_disk_conflict(&doc.core, &bytes),
+        Ok(DiskChange::Unchanged | DiskChange::Missing) => ConflictChoice::Overwrite,
+        Err(err) => {
+            report_error(&err);
+            ConflictChoice::Overwrite
+        }
+    }
+}
+
+fn resolve_disk_conflict(core: &Core, disk_bytes: &[u8]) -> ConflictChoice {
+    let name = core
+        .path()
+        .and_then(|path| path.file_name())
+        .and_then(|name| name.to_str())
+        .unwrap_or("Untitled");
+    let unsaved = if core.is_dirty() {
+        " Reloading discards your unsaved changes."
+    } else {
+        ""
+    };
+    loop {
+        let result = rfd::MessageDialog::new()
+            .set_level(rfd::MessageLevel::Warning)
+            .set_title("File Changed on Disk")
+            .set_description(format!(
+                "\"{name}\" was changed by another program.{unsaved} Reload it, overwrite \
+                 it with your version, or compare the two?"
+            ))
+            .set_buttons(rfd::MessageButtons::YesNoCancelCustom(
+                "Reload".to_string(),
+                "Overwrite".to_string(),
+                "Show Diff".to_string(),
+            ))
+            .show();
+        match result {
+            rfd::MessageDialogResult::Custom(label) if label == "Reload" => {
+                return ConflictChoice::Reload;
+            }
+            rfd::MessageDialogResult::Custom(label) if label == "Overwrite" => {
+                return ConflictChoice::Overwrite;
+            }
+            rfd::MessageDialogResult::Custom(label) if label == "Show Diff" => {
+                let mut disk = Core::new();
+                disk.load_from_bytes_as(disk_bytes, core.encoding());
+                let diff = format_diff(&line_diff(&disk.text(), &core.text()));
+                rfd::MessageDialog::new()
+                    .set_title("On Disk (-) vs. Yours (+)")
+                    .set_description(diff)
+                    .set_buttons(rfd::MessageButtons::Ok)
+                    .show();
+            }
+            _ => return ConflictChoice::Cancel,
+        }
+    }
+}
+
+const DIFF_CONTEXT_LINES: usize = 2;
+const DIFF_MAX_LINES: usize = 40;
+
+/// Renders a diff for a dialog: changed lines marked `-`/`+` with a little
+/// context, gaps shown as `…`, cut off after `DIFF_MAX_LINES`.
+fn format_diff(diff: &[DiffLine]) -> String {
+    let changed: Vec<usize> = diff
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, DiffLine::Same(_)))
+        .map(|(idx, _)| idx)
+        .collect();
+    if changed.is_empty() {
+        return "The contents are the same.".to_string();
+    }
+    let near_change = |idx: usize| {
+        changed
+            .iter()
+            .any(|changed| changed.abs_diff(idx) <= DIFF_CONTEXT_LINES)
+    };
+    let mut lines = Vec::new();
+    let mut skipped = false;
+    for (idx, line) in diff.iter().enumerate() {
+        if !near_change(idx) {
+            skipped = true;
+            continue;
+        }
+        if skipped && !lines.is_empty() {
+            lines.push("…".to_string());
+        }
+        skipped = false;
+        lines.push(match line {
+            DiffLine::Same(text) => format!("  {text}"),
+            DiffLine::Removed(text) => format!("- {text}"),
+            DiffLine::Added(text) => format!("+ {text}"),
+        });
+    }
+    if lines.len() > DIFF_MAX_LINES {
+        let hidden = lines.len() - DIFF_MAX_LINES;
+        lines.truncate(DIFF_MAX_LINES);
+        lines.push(format!("… {hidden} more lines"));
+    }
+    lines.join("\n")
+}
+
+/// Lists the undo branches in a dialog and offers to jump to the newest one
+/// that is not the current state.
+fn pick_undo_branch(core: &Core) -> Option<usize> {
+    let branches = core.undo_branches();
+    let now = std::time::SystemTime::now();
+    let mut description = branches
+        .iter()
+        .map(|branch| format_undo_branch(branch, now))
+        .collect::<Vec<_>>()
+        .join("\n");
+    if description.is_empty() {
+        description = "No undo history.".to_string();
+    }
+    let stats = core.undo_stats();
+    description.push_str(&format!(
+        "\n\n{} steps, {} KiB",
+        stats.entries,
+        stats.bytes.div_ceil(1024)
+    ));
+    let target = branches.iter().rev().find(|branch| !branch.current);
+    let dialog = rfd::MessageDialog::new()
+        .set_title("Undo History")
+        .set_description(description);
+    match target {
+        Some(branch) => {
+            let result = dialog
+                .set_buttons(rfd::MessageButtons::OkCancelCustom(
+                    format!("Go to #{}", branch.id),
+                    "Close".to_string(),
+                ))
+                .show();
+            (result == rfd::MessageDialogResult::Custom(format!("Go to #{}", branch.id)))
+                .then_some(branch.id)
+        }
+        None => {
+            dialog.show();
+            None
+        }
+    }
+}
+
+fn format_undo_branch(branch: &UndoBranch, now: std::time::SystemTime) -> String {
+    let age = now
+        .duration_since(branch.created_at)
+        .map(|age| age.as_secs())
+        .unwrap_or(0);
+    let age = match age {
+        0..=59 => format!("{age}s ago"),
+        60..=3599 => format!("{}m ago", age / 60),
+        _ => format!("{}h ago", age / 3600),
+    };
+    let current = if branch.current { "  (current)" } else { "" };
+    format!("#{}  {} changes  {}{}", branch.id, branch.changes, age, current)
+}
+
 fn pick_save_path(current_path: Option<&std::path::Path>) -> Option<PathBuf> {
     let dialog = rfd::FileDialog::new();
     let dialog = if let Some(path) = current_path {
@@ -606,46 +1347,211 @@ fn pick_save_path(current_path: Option<&std::path::Path>) -> Option<PathBuf> {
     dialog.save_file()
 }
 
+/// Files of the open documents, by document id, shared with the watcher
+/// thread.
+type WatchList = Arc<Mutex<Vec<(u64, PathBuf)>>>;
+
+/// Size and modification time of a watched file, or `None` while it is
+/// missing.
+type WatchedState = Option<(u64, Option<SystemTime>)>;
+
+fn sync_watched_files(watched: &Mutex<Vec<(u64, PathBuf)>>, documents: &[Document]) {
+    let files = documents
+        .iter()
+        .filter_map(|doc| Some((doc.id, doc.core.path()?.to_path_buf())))
+        .collect();
+    if let Ok(mut list) = watched.lock() {
+        *list = files;
+    }
+}
+
+/// Ids of the documents whose file changed since the previous poll. A file
+/// seen for the first time only records its state.
+fn poll_watched_files(
+    watched: &[(u64, PathBuf)],
+    last_seen: &mut HashMap<(u64, PathBuf), WatchedState>,
+) -> Vec<u64> {
+    last_seen.retain(|key, _| watched.contains(key));
+    let mut changed = Vec::new();
+    for key in watched {
+        let state = std::fs::metadata(&key.1)
+            .ok()
+            .map(|metadata| (metadata.len(), metadata.modified().ok()));
+        if let Some(previous) = last_seen.insert(key.clone(), state)
+            && previous != state
+        {
+            changed.push(key.0);
+        }
+    }
+    changed
+}
+
+/// Appends what was added to the end of a followed document's file and
+/// keeps the caret at the end. Returns false if the file changed some other
+/// way, e.g. it was truncated by log rotation.
+fn append_followed_file(doc: &mut Document) -> bool {
+    let (Some(path), Some(stamp)) = (doc.core.path(), doc.disk_stamp.as_mut()) else {
+        return false;
+    };
+    match storage::read_appended(path, stamp) {
+        Ok(Some(bytes)) => {
+            if !bytes.is_empty() {
+                doc.core.append_bytes(&bytes);
+                doc.core.move_document_end(false);
+            }
+            true
+        }
+        Ok(None) => false,
+        Err(err) => {
+            report_error(&err);
+            true
+        }
+    }
+}
+
+/// Reads the document's file again in its current encoding, replacing the
+/// text.
+fn reload_document(
+    doc: &mut Document,
+    proxy: &EventLoopProxy<AppEvent>,
+    request_id: u64,
+    persistent_undo: bool,
+) {
+    let Some(path) = doc.core.path().map(PathBuf::from) else {
+        return;
+    };
+    doc.active_open_request = Some(request_id);
+    start_open_task(
+        proxy.clone(),
+        doc.id,
+        request_id,
+        path,
+        Some(doc.core.encoding()),
+        persistent_undo,
+    );
+}
+
 fn start_open_task(
     proxy: EventLoopProxy<AppEvent>,
     doc_id: u64,
     request_id: u64,
     path: PathBuf,
+    encoding: Option<TextEncoding>,
+    persistent_undo: bool,
 ) {
     std::thread::spawn(move || {
         let result = std::fs::read(&path)
             .map_err(|err| CoreError::from_io(format!("read {}", path.display()), err));
+        let undo_history = if persistent_undo && result.is_ok() {
+            undo_store_path(&path).and_then(|store_path| std::fs::read(store_path).ok())
+        } else {
+            None
+        };
+        let stamp = result.as_ref().ok().and_then(|bytes| {
+            let metadata = std::fs::metadata(&path).ok()?;
+            Some(FileStamp::new(&metadata, bytes))
+        });
         let _ = proxy.send_event(AppEvent::OpenResult {
             doc_id,
             request_id,
             path,
+            encoding,
             result,
+            undo_history,
+            stamp,
         });
     });
 }
 
+/// Sidecar file holding the persistent undo history of `path`, keyed by a
+/// hash of the path. The history itself records the content hash it applies to.
+fn undo_store_path(path: &std::path::Path) -> Option<PathBuf> {
+    let home = std::env::var_os("HOME")?;
+    let key = content_hash(path.as_os_str().as_encoded_bytes());
+    Some(
+        PathBuf::from(home)
+            .join("Library/Application Support/Notepad Prototype/undo")
+            .join(format!("{key:016x}.undo")),
+    )
+}
+
+fn persist_undo_history(core: &Core) {
+    if let Some(store_path) = core.path().and_then(undo_store_path)
+        && let Some(history) = core.undo_history_bytes()
+    {
+        write_undo_history(&store_path, &history);
+    }
+}
+
+fn write_undo_history(store_path: &std::path::Path, history: &[u8]) {
+    let result = store_path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .map_err(|err| {
+            CoreError::from_io(format!("create undo store for {}", store_path.display()), err)
+        })
+        .and_then(|()| storage::save_atomic(store_path, history));
+    if let Err(err) = result {
+        report_error(&err);
+    }
+}
+
 fn start_save_task(
     proxy: EventLoopProxy<AppEvent>,
     doc_id: u64,
     request_id: u64,
     path: PathBuf,
-    encoding: TextEncoding,
-    text: String,
+    core: &mut Core,
+    backup: Option<BackupConfig>,
 ) {
+    let (text, encoding, line_ending) = (core.text(), core.encoding(), core.line_ending());
+    let save_point = core.save_point();
     std::thread::spawn(move || {
-        let bytes = Core::encode_text(&text, encoding);
-        let result = std::fs::write(&path, bytes)
-            .map_err(|err| CoreError::from_io(format!("write {}", path.display()), err));
+        let bytes = Core::encode_text(&text, encoding, line_ending);
+        let backup_result = backup
+            .as_ref()
+            .map_or(Ok(None), |config| storage::backup_previous(&path, config));
+        let (result, backup_error) = match backup_result {
+            Err(err) if backup.as_ref().is_some_and(|config| config.strict) => (Err(err), None),
+            Err(err) => (storage::save_atomic(&path, &bytes), Some(err)),
+            Ok(_) => (storage::save_atomic(&path, &bytes), None),
+        };
+        let stamp = result.as_ref().ok().and_then(|()| {
+            let metadata = std::fs::metadata(&path).ok()?;
+            Some(FileStamp::new(&metadata, &bytes))
+        });
         let _ = proxy.send_event(AppEvent::SaveResult {
             doc_id,
             request_id,
             path,
             encoding,
             result,
+            backup_error,
+            stamp,
+            save_point,
         });
     });
 }
 
+fn backup_config_from_env() -> Option<BackupConfig> {
+    let location = match std::env::var_os(BACKUP_DIR_ENV) {
+        Some(dir) if !dir.is_empty() => BackupLocation::Directory(PathBuf::from(dir)),
+        _ if std::env::var(BACKUP_ENV).is_ok_and(|value| value == "1") => {
+            BackupLocation::Sibling
+        }
+        _ => return None,
+    };
+    let generations = std::env::var(BACKUP_GENERATIONS_ENV)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(1);
+    Some(BackupConfig {
+        location,
+        generations,
+        strict: std::env::var(BACKUP_STRICT_ENV).is_ok_and(|value| value == "1"),
+    })
+}
+
 fn update_title(window: &winit::window::Window, core: &Core) {
     let name = core
         .path()
@@ -655,24 +1561,107 @@ fn update_title(window: &winit::window::Window, core: &Core) {
     let dirty = if core.is_dirty() { "*" } else { "" };
     let cursor = core.cursor();
     window.set_title(&format!(
-        "{name}{dirty} — {} (Ln {}, Col {})",
-        core.encoding().label(),
+        "{name}{dirty} — {} · {} — {} (Ln {}, Col {})",
+        encoding_label(core),
+        line_ending_label(core),
+        core.indentation().label(),
         cursor.line + 1,
         cursor.col + 1
     ));
 }
 
+/// The document's encoding, noting when it was guessed rather than known.
+fn encoding_label(core: &Core) -> String {
+    let encoding = core.encoding();
+    match core.detected_encoding() {
+        Some(detected) if detected.encoding == encoding && detected.confidence < 1.0 => {
+            format!(
+                "{} (detected, {:.0}%)",
+                encoding.label(),
+                detected.confidence * 100.0
+            )
+        }
+        _ => encoding.label().to_string(),
+    }
+}
+
+/// The line break style saves will use, noting a file that mixed styles.
+fn line_ending_label(core: &Core) -> String {
+    let label = core.line_ending().label();
+    if core.has_mixed_line_endings() {
+        format!("{label} (mixed)")
+    } else {
+        label.to_string()
+    }
+}
+
 fn refresh_ui(ui: &mut Ui, documents: &[Document], active_doc_index: usize) {
     let core = &documents[active_doc_index].core;
     let (line_numbers, digits) = build_line_numbers_text(core.line_count());
     ui.set_line_numbers(&line_numbers, digits);
     let caret = core.cursor_for_char(core.ime_cursor_char());
-    let caret_col = core.display_col(caret.line, caret.col);
-    ui.set_caret(caret.line, caret_col);
+    ui.set_caret(caret.line, caret.col);
+    ui.set_tab_width(core.indentation().tab_width);
     ui.set_text(&core.display_text());
+    ui.scroll_to_line(caret.line, core.line_count());
+    refresh_selection(ui, core);
     refresh_tabs(ui, documents, active_doc_index);
 }
 
+fn refresh_selection(ui: &mut Ui, core: &Core) {
+    let first_line = ui.scroll_line();
+    let last_line = first_line + ui.visible_line_count();
+    ui.set_selection(selection_spans(core, first_line, last_line));
+    ui.set_issues(issue_spans(core, first_line, last_line));
+}
+
+/// One span per character in `first_line..=last_line` that
+/// `Core::encoding_issue_cols` flags.
+fn issue_spans(core: &Core, first_line: usize, last_line: usize) -> Vec<SelectionSpan> {
+    let last_line = last_line.min(core.line_count().saturating_sub(1));
+    (first_line..=last_line)
+        .flat_map(|line| {
+            core.encoding_issue_cols(line)
+                .into_iter()
+                .map(move |col| SelectionSpan {
+                    line,
+                    start_col: col,
+                    end_col: col + 1,
+                    line_break: false,
+                })
+        })
+        .collect()
+}
+
+/// Splits the selection into per-line spans for the lines in
+/// `first_line..=last_line`.
+fn selection_spans(core: &Core, first_line: usize, last_line: usize) -> Vec<SelectionSpan> {
+    let Some((start, end)) = core.selection_range() else {
+        return Vec::new();
+    };
+    let start = core.cursor_for_char(start);
+    let end = core.cursor_for_char(end);
+    let mut spans = Vec::new();
+    for line in start.line.max(first_line)..=end.line.min(last_line) {
+        let start_col = if line == start.line { start.col } else { 0 };
+        let (end_col, line_break) = if line == end.line {
+            (end.col, false)
+        } else {
+            (core.line_len(line), true)
+        };
+        let span = SelectionSpan {
+            line,
+            start_col,
+            end_col,
+            line_break,
+        };
+        if span.end_col > span.start_col || span.line_break {
+            spans.push(span);
+        }
+    }
+    spans
+}
+
 fn refresh_tabs(ui: &mut Ui, documents: &[Document], active_doc_index: usize) {
     let tab_bar = build_tab_bar(documents, active_doc_index);
     ui.set_tabs(&tab_bar);
@@ -712,11 +1701,9 @@ fn doc_label(doc: &Document) -> String {
         .and_then(|path| path.file_name())
         .and_then(|name| name.to_str())
         .unwrap_or("Untitled");
-    if doc.core.is_dirty() {
-        format!("{name}*")
-    } else {
-        name.to_string()
-    }
+    let dirty = if doc.core.is_dirty() { "*" } else { "" };
+    let follow = if doc.follow { " (following)" } else { "" };
+    format!("{name}{dirty}{follow}")
 }
 
 fn switch_to_tab(documents: &mut [Document], active_doc_index: &mut usize, next_index: usize) {
@@ -735,9 +1722,7 @@ fn close_current_tab(documents: &mut Vec<Document>, active_doc_index: &mut usize
         return;
     }
     if documents.len() == 1 {
-        documents[0].core = Core::new();
-        documents[0].active_open_request = None;
-        documents[0].active_save_request = None;
+        documents[0] = Document::new(documents[0].id);
         return;
     }
     documents.remove(*active_doc_index);
@@ -760,20 +1745,56 @@ fn tab_index_from_key(ch: &str) -> Option<usize> {
 
 fn update_ime_cursor_area(window: &winit::window::Window, core: &Core, ui: &Ui) {
     let cursor = core.cursor_for_char(core.ime_cursor_char());
-    let display_col = core.display_col(cursor.line, cursor.col);
-    let (x, y, w, h) = ui.caret_rect(cursor.line, display_col);
+    let (x, y, w, h) = ui.caret_rect(cursor.line, cursor.col);
     window.set_ime_cursor_area(
         PhysicalPosition::new(x, y),
         PhysicalSize::new(w as u32, h as u32),
     );
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Direction {
     Left,
     Right,
     Up,
     Down,
+    WordLeft,
+    WordRight,
+    LineStart,
+    LineEnd,
+    DocumentStart,
+    DocumentEnd,
+    PageUp(usize),
+    PageDown(usize),
+}
+
+/// Maps a navigation key plus modifiers to a motion, following the macOS
+/// conventions (Cmd = line/document boundary, Option = word).
+fn direction_for_key(
+    key: NamedKey,
+    modifiers: winit::keyboard::ModifiersState,
+    page_lines: usize,
+) -> Option<Direction> {
+    let direction = match key {
+        NamedKey::ArrowLeft if modifiers.super_key() => Direction::LineStart,
+        NamedKey::ArrowLeft if modifiers.alt_key() => Direction::WordLeft,
+        NamedKey::ArrowLeft => Direction::Left,
+        NamedKey::ArrowRight if modifiers.super_key() => Direction::LineEnd,
+        NamedKey::ArrowRight if modifiers.alt_key() => Direction::WordRight,
+        NamedKey::ArrowRight => Direction::Right,
+        NamedKey::ArrowUp if modifiers.super_key() => Direction::DocumentStart,
+        NamedKey::ArrowUp => Direction::Up,
+        NamedKey::ArrowDown if modifiers.super_key() => Direction::DocumentEnd,
+        NamedKey::ArrowDown => Direction::Down,
+        NamedKey::Home if modifiers.control_key() => Direction::DocumentStart,
+        NamedKey::Home => Direction::LineStart,
+        NamedKey::End if modifiers.control_key() => Direction::DocumentEnd,
+        NamedKey::End => Direction::LineEnd,
+        NamedKey::PageUp => Direction::PageUp(page_lines),
+        NamedKey::PageDown => Direction::PageDown(page_lines),
+        _ => return None,
+    };
+    Some(direction)
 }
 
 fn move_cursor(core: &mut Core, direction: Direction, extend: bool) -> bool {
@@ -784,6 +1805,14 @@ fn move_cursor(core: &mut Core, direction: Direction, extend: bool) -> bool {
         Direction::Right => core.move_right(extend),
         Direction::Up => core.move_up(extend),
         Direction::Down => core.move_down(extend),
+        Direction::WordLeft => core.move_word_left(extend),
+        Direction::WordRight => core.move_word_right(extend),
+        Direction::LineStart => core.move_line_start(extend),
+        Direction::LineEnd => core.move_line_end(extend),
+        Direction::DocumentStart => core.move_document_start(extend),
+        Direction::DocumentEnd => core.move_document_end(extend),
+        Direction::PageUp(lines) => core.move_page_up(lines, extend),
+        Direction::PageDown(lines) => core.move_page_down(lines, extend),
     }
     core.cursor() != before_cursor || core.selection_range() != before_selection
 }
@@ -835,4 +1864,134 @@ mod tests {
         assert_eq!(lines[8], " 9");
         assert_eq!(lines[11], "12");
     }
+
+    #[test]
+    fn encoding_picker_filters_labels_case_insensitively() {
+        let mut picker = EncodingPicker::new(EncodingAction::Save, TextEncoding::ShiftJis);
+        assert_eq!(picker.chosen(), Some(TextEncoding::ShiftJis));
+        assert_eq!(picker.view().items[picker.selected], "Shift_JIS (current)");
+
+        picker.push_filter("EUC");
+        assert_eq!(picker.matches(), vec![TextEncoding::EucJp, TextEncoding::EucKr]);
+        picker.move_selection(1);
+        picker.move_selection(1);
+        assert_eq!(picker.chosen(), Some(TextEncoding::EucKr));
+        assert_eq!(picker.view().title, "Save with encoding: EUC");
+
+        picker.pop_filter();
+        picker.pop_filter();
+        picker.pop_filter();
+        picker.push_filter("1252");
+        assert_eq!(picker.chosen(), Some(TextEncoding::Windows1252));
+        picker.push_filter("x");
+        assert_eq!(picker.chosen(), None);
+        picker.move_selection(-1);
+        assert_eq!(picker.selected, 0);
+    }
+
+    #[test]
+    fn format_diff_shows_changes_with_context() {
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+        let new = "1\n2\nthree\n4\n5\n6\n7\n8\n9\nten\n";
+        assert_eq!(
+            format_diff(&line_diff(old, new)),
+            "  1\n  2\n- 3\n+ three\n  4\n  5\n…\n  8\n  9\n+ ten"
+        );
+        assert_eq!(format_diff(&line_diff("a", "a")), "The contents are the same.");
+    }
+
+    #[test]
+    fn poll_watched_files_reports_changes_after_the_first_poll() {
+        let dir = std::env::temp_dir().join(format!("notepad-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+        std::fs::write(&path, b"a").unwrap();
+        let watched = vec![(7, path.clone())];
+        let mut last_seen = HashMap::new();
+        assert!(poll_watched_files(&watched, &mut last_seen).is_empty());
+        assert!(poll_watched_files(&watched, &mut last_seen).is_empty());
+
+        std::fs::write(&path, b"ab").unwrap();
+        assert_eq!(poll_watched_files(&watched, &mut last_seen), vec![7]);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(poll_watched_files(&watched, &mut last_seen), vec![7]);
+        assert!(poll_watched_files(&[], &mut last_seen).is_empty());
+        assert!(last_seen.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn click_tracker_counts_double_and_triple_clicks() {
+        let mut tracker = ClickTracker::default();
+        let start = Instant::now();
+        let at = PhysicalPosition::new(10.0, 10.0);
+        assert_eq!(tracker.register(start, at), 1);
+        assert_eq!(tracker.register(start + Duration::from_millis(100), at), 2);
+        assert_eq!(tracker.register(start + Duration::from_millis(200), at), 3);
+        assert_eq!(tracker.register(start + Duration::from_millis(300), at), 1);
+    }
+
+    #[test]
+    fn click_tracker_resets_after_move_or_delay() {
+        let mut tracker = ClickTracker::default();
+        let start = Instant::now();
+        let at = PhysicalPosition::new(10.0, 10.0);
+        tracker.register(start, at);
+        assert_eq!(
+            tracker.register(start + Duration::from_millis(100), PhysicalPosition::new(40.0, 10.0)),
+            1
+        );
+        assert_eq!(tracker.register(start + Duration::from_secs(2), at), 1);
+    }
+
+    #[test]
+    fn selection_spans_cover_multi_line_selection() {
+        let mut core = Core::new();
+        core.insert_str("ab\n\tc\ndef");
+        core.set_cursor_line_col(0, 1, false);
+        core.set_cursor_line_col(2, 2, true);
+        let spans = selection_spans(&core, 0, 10);
+        assert_eq!(
+            spans,
+            vec![
+                SelectionSpan { line: 0, start_col: 1, end_col: 2, line_break: true },
+                SelectionSpan { line: 1, start_col: 0, end_col: 2, line_break: true },
+                SelectionSpan { line: 2, start_col: 0, end_col: 2, line_break: false },
+            ]
+        );
+    }
+
+    #[test]
+    fn selection_spans_clip_to_viewport() {
+        let mut core = Core::new();
+        core.insert_str("a\nb\nc\nd");
+        core.set_cursor_line_col(0, 0, false);
+        core.set_cursor_line_col(3, 1, true);
+        let lines: Vec<usize> = selection_spans(&core, 1, 2)
+            .iter()
+            .map(|span| span.line)
+            .collect();
+        assert_eq!(lines, vec![1, 2]);
+    }
+
+    #[test]
+    fn direction_for_key_follows_mac_modifiers() {
+        use winit::keyboard::ModifiersState;
+        let none = ModifiersState::empty();
+        assert_eq!(
+            direction_for_key(NamedKey::ArrowLeft, ModifiersState::SUPER, 20),
+            Some(Direction::LineStart)
+        );
+        assert_eq!(
+            direction_for_key(NamedKey::ArrowRight, ModifiersState::ALT, 20),
+            Some(Direction::WordRight)
+        );
+        assert_eq!(
+            direction_for_key(NamedKey::ArrowDown, ModifiersState::SUPER | ModifiersState::SHIFT, 20),
+            Some(Direction::DocumentEnd)
+        );
+        assert_eq!(direction_for_key(NamedKey::End, none, 20), Some(Direction::LineEnd));
+        assert_eq!(direction_for_key(NamedKey::PageUp, none, 20), Some(Direction::PageUp(20)));
+        assert_eq!(direction_for_key(NamedKey::Enter, none, 20), None);
+    }
 }
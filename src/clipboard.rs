@@ -0,0 +1,111 @@
+/// System clipboard with an in-process fallback. The fallback keeps copy and
+/// paste working inside the app when no system clipboard is reachable (for
+/// example in headless test runs); it is never consulted otherwise.
+pub struct Clipboard {
+    system: Option<Box<dyn SystemClipboard>>,
+    fallback: Option<String>,
+}
+
+/// The text operations used on the system clipboard.
+trait SystemClipboard {
+    fn get_text(&mut self) -> Result<String, arboard::Error>;
+    fn set_text(&mut self, text: String) -> Result<(), arboard::Error>;
+}
+
+impl SystemClipboard for arboard::Clipboard {
+    fn get_text(&mut self) -> Result<String, arboard::Error> {
+        arboard::Clipboard::get_text(self)
+    }
+
+    fn set_text(&mut self, text: String) -> Result<(), arboard::Error> {
+        arboard::Clipboard::set_text(self, text)
+    }
+}
+
+impl Clipboard {
+    pub fn new() -> Self {
+        let system = match arboard::Clipboard::new() {
+            Ok(clipboard) => Some(Box::new(clipboard) as Box<dyn SystemClipboard>),
+            Err(err) => {
+                eprintln!("[clipboard] system clipboard unavailable: {err}");
+                None
+            }
+        };
+        Self {
+            system,
+            fallback: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn in_process() -> Self {
+        Self {
+            system: None,
+            fallback: None,
+        }
+    }
+
+    pub fn set_text(&mut self, text: String) {
+        match self.system.as_mut() {
+            Some(system) => {
+                if let Err(err) = system.set_text(text) {
+                    eprintln!("[clipboard] failed to write system clipboard: {err}");
+                }
+            }
+            None => self.fallback = Some(text),
+        }
+    }
+
+    /// The clipboard's text, or `None` if it is empty or holds something
+    /// else, such as an image.
+    pub fn get_text(&mut self) -> Option<String> {
+        let Some(system) = self.system.as_mut() else {
+            return self.fallback.clone();
+        };
+        match system.get_text() {
+            Ok(text) => Some(text),
+            Err(arboard::Error::ContentNotAvailable) => None,
+            Err(err) => {
+                eprintln!("[clipboard] failed to read system clipboard: {err}");
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A system clipboard currently holding non-text content.
+    struct ImageOnly;
+
+    impl SystemClipboard for ImageOnly {
+        fn get_text(&mut self) -> Result<String, arboard::Error> {
+            Err(arboard::Error::ContentNotAvailable)
+        }
+
+        fn set_text(&mut self, _text: String) -> Result<(), arboard::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn in_process_clipboard_round_trips() {
+        let mut clipboard = Clipboard::in_process();
+        assert_eq!(clipboard.get_text(), None);
+        clipboard.set_text("あいう".to_string());
+        assert_eq!(clipboard.get_text().as_deref(), Some("あいう"));
+    }
+
+    #[test]
+    fn failed_system_read_does_not_paste_an_old_copy() {
+        let mut clipboard = Clipboard {
+            system: Some(Box::new(ImageOnly)),
+            fallback: None,
+        };
+        clipboard.set_text("copied earlier".to_string());
+        assert_eq!(clipboard.get_text(), None);
+        assert_eq!(clipboard.fallback, None);
+    }
+}
@@ -1,7 +1,10 @@
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use encoding_rs::{Encoding, SHIFT_JIS, UTF_16BE, UTF_16LE, UTF_8};
+use encoding_rs::{CoderResult, Decoder, DecoderResult, EncoderResult, Encoding};
 use ropey::Rope;
+use unicode_segmentation::{GraphemeCursor, UnicodeSegmentation};
+use unicode_width::UnicodeWidthStr;
 
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub struct Cursor {
@@ -9,66 +12,513 @@ pub struct Cursor {
     pub col: usize,
 }
 
+/// Every character encoding encoding_rs can both decode and encode, except
+/// the "replacement" encoding.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TextEncoding {
     Utf8,
+    /// UTF-8 written with a leading byte order mark.
+    Utf8Bom,
     Utf16Le,
     Utf16Be,
     ShiftJis,
+    EucJp,
+    Iso2022Jp,
+    Gb18030,
+    Gbk,
+    Big5,
+    EucKr,
+    Windows1250,
+    Windows1251,
+    Windows1252,
+    Windows1253,
+    Windows1254,
+    Windows1255,
+    Windows1256,
+    Windows1257,
+    Windows1258,
+    Windows874,
+    Iso8859_2,
+    Iso8859_3,
+    Iso8859_4,
+    Iso8859_5,
+    Iso8859_6,
+    Iso8859_7,
+    Iso8859_8,
+    Iso8859_8I,
+    Iso8859_10,
+    Iso8859_13,
+    Iso8859_14,
+    Iso8859_15,
+    Iso8859_16,
+    Koi8R,
+    Koi8U,
+    Ibm866,
+    Macintosh,
+    XMacCyrillic,
+    XUserDefined,
 }
 
 impl TextEncoding {
+    pub const ALL: [TextEncoding; 40] = [
+        TextEncoding::Utf8,
+        TextEncoding::Utf8Bom,
+        TextEncoding::Utf16Le,
+        TextEncoding::Utf16Be,
+        TextEncoding::ShiftJis,
+        TextEncoding::EucJp,
+        TextEncoding::Iso2022Jp,
+        TextEncoding::Gb18030,
+        TextEncoding::Gbk,
+        TextEncoding::Big5,
+        TextEncoding::EucKr,
+        TextEncoding::Windows1250,
+        TextEncoding::Windows1251,
+        TextEncoding::Windows1252,
+        TextEncoding::Windows1253,
+        TextEncoding::Windows1254,
+        TextEncoding::Windows1255,
+        TextEncoding::Windows1256,
+        TextEncoding::Windows1257,
+        TextEncoding::Windows1258,
+        TextEncoding::Windows874,
+        TextEncoding::Iso8859_2,
+        TextEncoding::Iso8859_3,
+        TextEncoding::Iso8859_4,
+        TextEncoding::Iso8859_5,
+        TextEncoding::Iso8859_6,
+        TextEncoding::Iso8859_7,
+        TextEncoding::Iso8859_8,
+        TextEncoding::Iso8859_8I,
+        TextEncoding::Iso8859_10,
+        TextEncoding::Iso8859_13,
+        TextEncoding::Iso8859_14,
+        TextEncoding::Iso8859_15,
+        TextEncoding::Iso8859_16,
+        TextEncoding::Koi8R,
+        TextEncoding::Koi8U,
+        TextEncoding::Ibm866,
+        TextEncoding::Macintosh,
+        TextEncoding::XMacCyrillic,
+        TextEncoding::XUserDefined,
+    ];
+
+    /// The WHATWG name of the encoding, e.g. "Shift_JIS" or "windows-1252".
     pub fn label(self) -> &'static str {
         match self {
-            TextEncoding::Utf8 => "UTF-8",
-            TextEncoding::Utf16Le => "UTF-16LE",
-            TextEncoding::Utf16Be => "UTF-16BE",
-            TextEncoding::ShiftJis => "Shift_JIS",
+            TextEncoding::Utf8Bom => "UTF-8 with BOM",
+            _ => self.encoding().name(),
         }
     }
 
     pub fn encoding(self) -> &'static Encoding {
         match self {
-            TextEncoding::Utf8 => UTF_8,
-            TextEncoding::Utf16Le => UTF_16LE,
-            TextEncoding::Utf16Be => UTF_16BE,
-            TextEncoding::ShiftJis => SHIFT_JIS,
+            TextEncoding::Utf8 | TextEncoding::Utf8Bom => encoding_rs::UTF_8,
+            TextEncoding::Utf16Le => encoding_rs::UTF_16LE,
+            TextEncoding::Utf16Be => encoding_rs::UTF_16BE,
+            TextEncoding::ShiftJis => encoding_rs::SHIFT_JIS,
+            TextEncoding::EucJp => encoding_rs::EUC_JP,
+            TextEncoding::Iso2022Jp => encoding_rs::ISO_2022_JP,
+            TextEncoding::Gb18030 => encoding_rs::GB18030,
+            TextEncoding::Gbk => encoding_rs::GBK,
+            TextEncoding::Big5 => encoding_rs::BIG5,
+            TextEncoding::EucKr => encoding_rs::EUC_KR,
+            TextEncoding::Windows1250 => encoding_rs::WINDOWS_1250,
+            TextEncoding::Windows1251 => encoding_rs::WINDOWS_1251,
+            TextEncoding::Windows1252 => encoding_rs::WINDOWS_1252,
+            TextEncoding::Windows1253 => encoding_rs::WINDOWS_1253,
+            TextEncoding::Windows1254 => encoding_rs::WINDOWS_1254,
+            TextEncoding::Windows1255 => encoding_rs::WINDOWS_1255,
+            TextEncoding::Windows1256 => encoding_rs::WINDOWS_1256,
+            TextEncoding::Windows1257 => encoding_rs::WINDOWS_1257,
+            TextEncoding::Windows1258 => encoding_rs::WINDOWS_1258,
+            TextEncoding::Windows874 => encoding_rs::WINDOWS_874,
+            TextEncoding::Iso8859_2 => encoding_rs::ISO_8859_2,
+            TextEncoding::Iso8859_3 => encoding_rs::ISO_8859_3,
+            TextEncoding::Iso8859_4 => encoding_rs::ISO_8859_4,
+            TextEncoding::Iso8859_5 => encoding_rs::ISO_8859_5,
+            TextEncoding::Iso8859_6 => encoding_rs::ISO_8859_6,
+            TextEncoding::Iso8859_7 => encoding_rs::ISO_8859_7,
+            TextEncoding::Iso8859_8 => encoding_rs::ISO_8859_8,
+            TextEncoding::Iso8859_8I => encoding_rs::ISO_8859_8_I,
+            TextEncoding::Iso8859_10 => encoding_rs::ISO_8859_10,
+            TextEncoding::Iso8859_13 => encoding_rs::ISO_8859_13,
+            TextEncoding::Iso8859_14 => encoding_rs::ISO_8859_14,
+            TextEncoding::Iso8859_15 => encoding_rs::ISO_8859_15,
+            TextEncoding::Iso8859_16 => encoding_rs::ISO_8859_16,
+            TextEncoding::Koi8R => encoding_rs::KOI8_R,
+            TextEncoding::Koi8U => encoding_rs::KOI8_U,
+            TextEncoding::Ibm866 => encoding_rs::IBM866,
+            TextEncoding::Macintosh => encoding_rs::MACINTOSH,
+            TextEncoding::XMacCyrillic => encoding_rs::X_MAC_CYRILLIC,
+            TextEncoding::XUserDefined => encoding_rs::X_USER_DEFINED,
         }
     }
 
     pub fn bom(self) -> &'static [u8] {
         match self {
-            TextEncoding::Utf8 => &[],
+            TextEncoding::Utf8Bom => &[0xEF, 0xBB, 0xBF],
             TextEncoding::Utf16Le => &[0xFF, 0xFE],
             TextEncoding::Utf16Be => &[0xFE, 0xFF],
-            TextEncoding::ShiftJis => &[],
+            _ => &[],
         }
     }
 
+    /// UTF-8 maps to `Utf8`; whether a BOM is written is up to the caller.
     pub fn from_encoding(encoding: &'static Encoding) -> Option<Self> {
-        if encoding == UTF_8 {
-            Some(TextEncoding::Utf8)
-        } else if encoding == UTF_16LE {
-            Some(TextEncoding::Utf16Le)
-        } else if encoding == UTF_16BE {
-            Some(TextEncoding::Utf16Be)
-        } else if encoding == SHIFT_JIS {
-            Some(TextEncoding::ShiftJis)
+        Self::ALL
+            .into_iter()
+            .find(|candidate| candidate.encoding() == encoding)
+    }
+
+    /// Whether every Unicode scalar value can be encoded.
+    pub fn is_unicode(self) -> bool {
+        matches!(
+            self,
+            TextEncoding::Utf8
+                | TextEncoding::Utf8Bom
+                | TextEncoding::Utf16Le
+                | TextEncoding::Utf16Be
+                | TextEncoding::Gb18030
+        )
+    }
+}
+
+/// Result of `detect_encoding`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DetectedEncoding {
+    pub encoding: TextEncoding,
+    /// Length of the byte order mark that identified the encoding, if any.
+    pub bom_len: usize,
+    /// How sure the guess is, from 0.0 to 1.0. A BOM or pure ASCII gives 1.0.
+    pub confidence: f32,
+}
+
+impl DetectedEncoding {
+    fn new(encoding: TextEncoding, confidence: f32) -> Self {
+        Self {
+            encoding,
+            bom_len: 0,
+            confidence: confidence.clamp(0.0, 1.0),
+        }
+    }
+}
+
+/// Guesses the encoding of `bytes`: a BOM wins outright, then UTF-16 is
+/// recognised by its pattern of NUL bytes, ASCII and valid UTF-8 are taken as
+/// UTF-8, and
+/// anything else is scored as Shift_JIS and EUC-JP by how much of it decodes
+/// to common Japanese text.
+pub fn detect_encoding(bytes: &[u8]) -> DetectedEncoding {
+    if let Some((encoding, bom_len)) = Encoding::for_bom(bytes)
+        && let Some(encoding) = TextEncoding::from_encoding(encoding)
+    {
+        // Keep the BOM on save so the file round-trips byte for byte.
+        let encoding = if encoding == TextEncoding::Utf8 {
+            TextEncoding::Utf8Bom
+        } else {
+            encoding
+        };
+        return DetectedEncoding {
+            encoding,
+            bom_len,
+            confidence: 1.0,
+        };
+    }
+    if let Some(detected) = detect_utf16(bytes) {
+        return detected;
+    }
+    if let Some(detected) = detect_iso_2022_jp(bytes) {
+        return detected;
+    }
+    if bytes.is_ascii() {
+        return DetectedEncoding::new(TextEncoding::Utf8, 1.0);
+    }
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        // Each valid multi-byte sequence makes a legacy encoding less likely.
+        let multibyte = text.chars().filter(|ch| !ch.is_ascii()).count();
+        let confidence = 1.0 - 0.5f32.powi(multibyte as i32 + 3);
+        return DetectedEncoding::new(TextEncoding::Utf8, confidence.min(MAX_GUESS_CONFIDENCE));
+    }
+    // Shift_JIS wins ties: it is the more common of the two, and the same
+    // bytes often decode cleanly as either.
+    let (sjis_share, sjis_chars) = japanese_score(bytes, TextEncoding::ShiftJis);
+    let (euc_share, euc_chars) = japanese_score(bytes, TextEncoding::EucJp);
+    let (encoding, share, chars, margin) = if euc_share > sjis_share {
+        (TextEncoding::EucJp, euc_share, euc_chars, euc_share - sjis_share)
+    } else {
+        (TextEncoding::ShiftJis, sjis_share, sjis_chars, sjis_share - euc_share)
+    };
+    // A handful of characters, or bytes that read as well in the other
+    // encoding, say little either way.
+    let sample = 1.0 - 0.5f32.powi(chars as i32 + 1);
+    let confidence = share * sample * (0.5 + margin / 2.0);
+    // Windows-1252 maps every byte, so it is the fallback for 8-bit text
+    // that doesn't look Japanese.
+    if confidence <= WINDOWS_1252_PRIOR {
+        return DetectedEncoding::new(TextEncoding::Windows1252, WINDOWS_1252_PRIOR);
+    }
+    DetectedEncoding::new(encoding, confidence.min(MAX_GUESS_CONFIDENCE))
+}
+
+const WINDOWS_1252_PRIOR: f32 = 0.3;
+
+/// Ceiling for guesses from the content alone, so they always read as
+/// "detected" rather than certain.
+const MAX_GUESS_CONFIDENCE: f32 = 0.99;
+
+/// ISO-2022-JP is 7-bit, so it would otherwise pass as ASCII; it is
+/// recognized by its JIS X 0208 / JIS X 0201 escape sequences.
+fn detect_iso_2022_jp(bytes: &[u8]) -> Option<DetectedEncoding> {
+    let has_escape = bytes.windows(3).any(|window| {
+        matches!(window, [0x1B, b'$', b'B' | b'@'] | [0x1B, b'(', b'J' | b'I'])
+    });
+    if !has_escape || !bytes.is_ascii() {
+        return None;
+    }
+    TextEncoding::Iso2022Jp
+        .encoding()
+        .decode_without_bom_handling_and_without_replacement(bytes)?;
+    Some(DetectedEncoding::new(TextEncoding::Iso2022Jp, 0.95))
+}
+
+/// UTF-16 text that is mostly Latin has a NUL in every other byte; which
+/// half holds them gives the byte order.
+fn detect_utf16(bytes: &[u8]) -> Option<DetectedEncoding> {
+    if bytes.len() < 2 || !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+    let pairs = bytes.len() / 2;
+    let even = bytes.iter().step_by(2).filter(|byte| **byte == 0).count();
+    let odd = bytes.iter().skip(1).step_by(2).filter(|byte| **byte == 0).count();
+    let (encoding, zeros, other) = if odd > even {
+        (TextEncoding::Utf16Le, odd, even)
+    } else {
+        (TextEncoding::Utf16Be, even, odd)
+    };
+    let ratio = zeros as f32 / pairs as f32;
+    if ratio < 0.3 || other as f32 / pairs as f32 > 0.05 {
+        return None;
+    }
+    encoding
+        .encoding()
+        .decode_without_bom_handling_and_without_replacement(bytes)?;
+    Some(DetectedEncoding::new(encoding, 0.5 + ratio / 2.0))
+}
+
+/// Decodes `bytes` as `encoding`, replacing malformed sequences with U+FFFD
+/// and returning the byte offset of each.
+fn decode_reporting(encoding: TextEncoding, bytes: &[u8]) -> (String, Vec<usize>) {
+    let mut decoder = encoding.encoding().new_decoder_without_bom_handling();
+    let mut output = String::new();
+    let mut malformed = Vec::new();
+    let mut read = 0;
+    loop {
+        let remaining = bytes.len() - read;
+        output.reserve(
+            decoder
+                .max_utf8_buffer_length_without_replacement(remaining)
+                .unwrap_or(remaining * 3 + 16),
+        );
+        let (result, consumed) =
+            decoder.decode_to_string_without_replacement(&bytes[read..], &mut output, true);
+        read += consumed;
+        match result {
+            DecoderResult::InputEmpty => break,
+            DecoderResult::OutputFull => {}
+            DecoderResult::Malformed(bad, extra) => {
+                malformed.push(read - extra as usize - bad as usize);
+                output.push(char::REPLACEMENT_CHARACTER);
+            }
+        }
+    }
+    (output, malformed)
+}
+
+/// Char indices in `text` of characters `encoding` cannot represent.
+fn unencodable_chars(text: &str, encoding: TextEncoding) -> Vec<usize> {
+    if encoding.is_unicode() || text.is_ascii() {
+        return Vec::new();
+    }
+    let mut encoder = encoding.encoding().new_encoder();
+    let mut positions = Vec::new();
+    let mut buffer = vec![0; 1024];
+    let mut read = 0;
+    let mut counted_bytes = 0;
+    let mut chars = 0;
+    loop {
+        let (result, consumed, _) =
+            encoder.encode_from_utf8_without_replacement(&text[read..], &mut buffer, true);
+        read += consumed;
+        match result {
+            EncoderResult::InputEmpty => break,
+            EncoderResult::OutputFull => {}
+            EncoderResult::Unmappable(ch) => {
+                let start = read - ch.len_utf8();
+                chars += text[counted_bytes..start].chars().count();
+                counted_bytes = start;
+                positions.push(chars);
+            }
+        }
+    }
+    positions
+}
+
+/// Share of the non-ASCII chars in `bytes`, decoded as `encoding`, that are
+/// kana, kanji or full-width punctuation, and how many such chars there are.
+/// Zero if the bytes are not valid in that encoding.
+fn japanese_score(bytes: &[u8], encoding: TextEncoding) -> (f32, usize) {
+    let Some(text) = encoding
+        .encoding()
+        .decode_without_bom_handling_and_without_replacement(bytes)
+    else {
+        return (0.0, 0);
+    };
+    let chars: Vec<char> = text.chars().collect();
+    let beside_non_ascii = |index: usize| {
+        [index.wrapping_sub(1), index + 1]
+            .into_iter()
+            .any(|neighbour| chars.get(neighbour).is_some_and(|ch| !ch.is_ascii()))
+    };
+    let mut non_ascii = 0;
+    let mut japanese = 0;
+    for (index, ch) in chars.iter().enumerate().filter(|(_, ch)| !ch.is_ascii()) {
+        non_ascii += 1;
+        let is_japanese = match ch {
+            '\u{3000}'..='\u{30FF}' | '\u{4E00}'..='\u{9FFF}' | '\u{FF01}'..='\u{FF5E}' => true,
+            // Half-width katakana share their bytes with Windows-1252's
+            // accented capitals ("Straße", "ÄRGER"); a lone one among ASCII
+            // is more likely Latin text.
+            '\u{FF61}'..='\u{FF9F}' => beside_non_ascii(index),
+            _ => false,
+        };
+        if is_japanese {
+            japanese += 1;
+        }
+    }
+    if non_ascii == 0 {
+        return (0.0, 0);
+    }
+    (japanese as f32 / non_ascii as f32, japanese)
+}
+
+/// Per-document indentation settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Indentation {
+    pub tab_width: usize,
+    /// Insert spaces instead of a tab character when Tab is pressed.
+    pub soft_tabs: bool,
+}
+
+impl Default for Indentation {
+    fn default() -> Self {
+        Self {
+            tab_width: 4,
+            soft_tabs: false,
+        }
+    }
+}
+
+impl Indentation {
+    pub const TAB_WIDTHS: [usize; 3] = [2, 4, 8];
+
+    pub fn label(self) -> String {
+        if self.soft_tabs {
+            format!("Spaces: {}", self.tab_width)
+        } else {
+            format!("Tab Size: {}", self.tab_width)
+        }
+    }
+
+    /// Next tab width in `TAB_WIDTHS`, wrapping around.
+    pub fn next_tab_width(self) -> usize {
+        let index = Self::TAB_WIDTHS
+            .iter()
+            .position(|width| *width == self.tab_width)
+            .map(|index| (index + 1) % Self::TAB_WIDTHS.len())
+            .unwrap_or(0);
+        Self::TAB_WIDTHS[index]
+    }
+
+    fn unit(self) -> String {
+        if self.soft_tabs {
+            " ".repeat(self.tab_width)
         } else {
-            None
+            "\t".to_string()
+        }
+    }
+}
+
+/// Line break style of a file. The rope always holds `\n`; the style is
+/// applied again by `Core::encode_text`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+    Cr,
+}
+
+impl LineEnding {
+    pub fn label(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::CrLf => "CRLF",
+            LineEnding::Cr => "CR",
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Cr => "\r",
         }
     }
 
+    /// Next style in LF → CRLF → CR order, wrapping around.
     pub fn next(self) -> Self {
         match self {
-            TextEncoding::Utf8 => TextEncoding::Utf16Le,
-            TextEncoding::Utf16Le => TextEncoding::Utf16Be,
-            TextEncoding::Utf16Be => TextEncoding::ShiftJis,
-            TextEncoding::ShiftJis => TextEncoding::Utf8,
+            LineEnding::Lf => LineEnding::CrLf,
+            LineEnding::CrLf => LineEnding::Cr,
+            LineEnding::Cr => LineEnding::Lf,
         }
     }
 }
 
+/// The most common line break style in `text` (LF if there are none or on a
+/// tie), and whether more than one style occurs.
+pub fn detect_line_ending(text: &str) -> (LineEnding, bool) {
+    let (mut lf, mut crlf, mut cr) = (0usize, 0usize, 0usize);
+    let mut bytes = text.bytes().peekable();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'\r' if bytes.peek() == Some(&b'\n') => {
+                bytes.next();
+                crlf += 1;
+            }
+            b'\r' => cr += 1,
+            b'\n' => lf += 1,
+            _ => {}
+        }
+    }
+    let styles = [(LineEnding::Lf, lf), (LineEnding::CrLf, crlf), (LineEnding::Cr, cr)];
+    let mixed = styles.iter().filter(|(_, count)| *count > 0).count() > 1;
+    let dominant = styles
+        .into_iter()
+        .rev()
+        .max_by_key(|(_, count)| *count)
+        .map_or(LineEnding::Lf, |(style, _)| style);
+    (dominant, mixed)
+}
+
+/// Rewrites CRLF and lone CR line breaks as `\n`.
+fn normalize_line_endings(text: &str) -> std::borrow::Cow<'_, str> {
+    if !text.contains('\r') {
+        return std::borrow::Cow::Borrowed(text);
+    }
+    std::borrow::Cow::Owned(text.replace("\r\n", "\n").replace('\r', "\n"))
+}
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub enum CoreError {
@@ -81,6 +531,8 @@ pub struct SystemError {
     pub kind: SystemErrorKind,
     pub context: String,
     pub retriable: bool,
+    /// Byte offsets in the input that caused an `Encoding` error.
+    pub positions: Vec<usize>,
 }
 
 #[allow(dead_code)]
@@ -97,6 +549,8 @@ pub enum SystemErrorKind {
 pub struct DomainError {
     pub kind: DomainErrorKind,
     pub context: String,
+    /// Char indices in the document that the error refers to.
+    pub positions: Vec<usize>,
 }
 
 #[allow(dead_code)]
@@ -106,6 +560,8 @@ pub enum DomainErrorKind {
     InvalidState,
     OutOfRange,
     EmptySelection,
+    /// The text has characters the target encoding cannot represent.
+    Unencodable,
 }
 
 #[derive(Debug, Clone)]
@@ -119,6 +575,398 @@ struct Edit {
     kind: EditKind,
     cursor_before: usize,
     cursor_after: usize,
+    anchor_before: Option<usize>,
+    anchor_after: Option<usize>,
+}
+
+/// Edits that undo and redo as one step.
+#[derive(Debug, Clone)]
+struct UndoGroup {
+    edits: Vec<Edit>,
+    /// Set while further single-grapheme edits of the same kind may still be
+    /// merged into this group.
+    coalesce: Option<Coalesce>,
+    updated_at: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Coalesce {
+    Typing,
+    Backspace,
+    DeleteForward,
+}
+
+/// Undo history kept as a tree: editing after an undo starts a new branch
+/// instead of discarding the undone states. Node 0 is the text as loaded and
+/// has no edits; node ids grow in creation order.
+#[derive(Debug, Clone)]
+struct UndoTree {
+    nodes: Vec<UndoNode>,
+    current: usize,
+    /// Running total of `UndoNode::bytes` over all nodes.
+    bytes: usize,
+}
+
+#[derive(Debug, Clone)]
+struct UndoNode {
+    parent: usize,
+    /// Child that `redo` follows: the one most recently created or undone.
+    redo_child: Option<usize>,
+    group: UndoGroup,
+    created_at: SystemTime,
+}
+
+/// A leaf of the undo tree, as listed by `Core::undo_branches`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UndoBranch {
+    pub id: usize,
+    /// Undo steps between the loaded text and this state.
+    pub changes: usize,
+    pub created_at: SystemTime,
+    pub current: bool,
+}
+
+/// Size of the undo history, as reported by `Core::undo_stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UndoStats {
+    /// Undo steps (groups) in the tree, across all branches.
+    pub entries: usize,
+    /// Approximate memory held by those steps.
+    pub bytes: usize,
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.bytes.len() {
+            return None;
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Some(head)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn usize(&mut self) -> Option<usize> {
+        usize::try_from(self.u64()?).ok()
+    }
+
+    fn opt_usize(&mut self) -> Option<Option<usize>> {
+        match self.u64()? {
+            u64::MAX => Some(None),
+            value => usize::try_from(value).ok().map(Some),
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.usize()?;
+        String::from_utf8(self.take(len)?.to_vec()).ok()
+    }
+}
+
+impl Edit {
+    /// Approximate memory held by the edit, text included.
+    fn bytes(&self) -> usize {
+        let text = match &self.kind {
+            EditKind::Insert { text, .. } | EditKind::Delete { text, .. } => text.len(),
+            EditKind::Replace { deleted, inserted, .. } => deleted.len() + inserted.len(),
+        };
+        std::mem::size_of::<Edit>() + text
+    }
+}
+
+impl UndoNode {
+    fn bytes(&self) -> usize {
+        std::mem::size_of::<UndoNode>() + self.group.edits.iter().map(Edit::bytes).sum::<usize>()
+    }
+}
+
+impl UndoTree {
+    fn new() -> Self {
+        Self::from_nodes(
+            vec![UndoNode {
+                parent: 0,
+                redo_child: None,
+                group: UndoGroup {
+                    edits: Vec::new(),
+                    coalesce: None,
+                    updated_at: Instant::now(),
+                },
+                created_at: SystemTime::now(),
+            }],
+            0,
+        )
+    }
+
+    fn from_nodes(nodes: Vec<UndoNode>, current: usize) -> Self {
+        let bytes = nodes.iter().map(UndoNode::bytes).sum();
+        Self {
+            nodes,
+            current,
+            bytes,
+        }
+    }
+
+    /// Once the tree outgrows `budget` bytes, drops the oldest history until
+    /// it fits in three quarters of it, so the next few edits don't each
+    /// rebuild the tree. First a later state on the path to the current one
+    /// becomes the new root, taking with it every branch that forks off
+    /// before it; then, if still needed, the oldest branches off that path
+    /// are dropped. The current step is always kept. Returns the new id of
+    /// every surviving node, indexed by old id, or `None` if nothing was
+    /// dropped.
+    fn prune_to(&mut self, budget: usize) -> Option<Vec<Option<usize>>> {
+        if self.bytes <= budget {
+            return None;
+        }
+        let target = budget - budget / 4;
+        let mut subtree: Vec<usize> = self.nodes.iter().map(UndoNode::bytes).collect();
+        for id in (1..self.nodes.len()).rev() {
+            let parent = self.nodes[id].parent;
+            subtree[parent] += subtree[id];
+        }
+        let root_bytes = |id: usize| subtree[id] - self.nodes[id].bytes() + size_of::<UndoNode>();
+        // Candidate roots from oldest to newest, excluding the current node.
+        let mut path = self.ancestors(self.current);
+        path.reverse();
+        let candidates = path.get(1..path.len().saturating_sub(1)).unwrap_or_default();
+        let new_root = candidates
+            .iter()
+            .copied()
+            .find(|&id| root_bytes(id) <= target)
+            .or_else(|| candidates.last().copied())
+            .unwrap_or(0);
+        let mut total = root_bytes(new_root);
+
+        let on_path = &path[path.iter().position(|id| *id == new_root).unwrap_or(0)..];
+        let mut dropped = vec![false; self.nodes.len()];
+        for id in new_root + 1..self.nodes.len() {
+            if total <= target {
+                break;
+            }
+            let parent = self.nodes[id].parent;
+            if on_path.contains(&parent) && !on_path.contains(&id) {
+                dropped[id] = true;
+                total -= subtree[id];
+            }
+        }
+        if new_root == 0 && !dropped.contains(&true) {
+            return None;
+        }
+
+        let mut remap = vec![None; self.nodes.len()];
+        remap[new_root] = Some(0);
+        let mut next_id = 1;
+        // Parents always have smaller ids than their children.
+        for id in new_root + 1..self.nodes.len() {
+            if !dropped[id] && remap[self.nodes[id].parent].is_some() {
+                remap[id] = Some(next_id);
+                next_id += 1;
+            }
+        }
+        let nodes = std::mem::take(&mut self.nodes);
+        let mut kept = Vec::with_capacity(next_id);
+        for (id, mut node) in nodes.into_iter().enumerate() {
+            if remap[id].is_none() {
+                continue;
+            }
+            if id == new_root {
+                node.parent = 0;
+                node.group.edits = Vec::new();
+            } else {
+                node.parent = remap[node.parent].unwrap_or(0);
+            }
+            node.redo_child = node.redo_child.and_then(|child| remap[child]);
+            kept.push(node);
+        }
+        let current = remap[self.current].unwrap_or(0);
+        *self = Self::from_nodes(kept, current);
+        Some(remap)
+    }
+
+    /// The group that produced the current state, unless that is the root.
+    fn current_group_mut(&mut self) -> Option<&mut UndoGroup> {
+        match self.current {
+            0 => None,
+            current => Some(&mut self.nodes[current].group),
+        }
+    }
+
+    fn push(&mut self, group: UndoGroup) {
+        let id = self.nodes.len();
+        let node = UndoNode {
+            parent: self.current,
+            redo_child: None,
+            group,
+            created_at: SystemTime::now(),
+        };
+        self.bytes += node.bytes();
+        self.nodes.push(node);
+        self.nodes[self.current].redo_child = Some(id);
+        self.current = id;
+    }
+
+    fn depth(&self, mut node: usize) -> usize {
+        let mut depth = 0;
+        while node != 0 {
+            node = self.nodes[node].parent;
+            depth += 1;
+        }
+        depth
+    }
+
+    const MAGIC: &'static [u8] = b"NPUNDO1\n";
+
+    /// Serializes the tree for the persistent undo store. `saved` is the node
+    /// matching the text on disk, whose content hash is `text_hash`.
+    fn to_bytes(&self, saved: usize, text_hash: u64) -> Vec<u8> {
+        let mut out = Self::MAGIC.to_vec();
+        let put = |out: &mut Vec<u8>, value: u64| out.extend_from_slice(&value.to_le_bytes());
+        let put_opt = |out: &mut Vec<u8>, value: Option<usize>| {
+            put(out, value.map_or(u64::MAX, |value| value as u64))
+        };
+        let put_str = |out: &mut Vec<u8>, text: &str| {
+            put(out, text.len() as u64);
+            out.extend_from_slice(text.as_bytes());
+        };
+        put(&mut out, text_hash);
+        put(&mut out, saved as u64);
+        put(&mut out, self.nodes.len() as u64);
+        for node in &self.nodes {
+            let created_ms = node
+                .created_at
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |age| age.as_millis() as u64);
+            put(&mut out, node.parent as u64);
+            put_opt(&mut out, node.redo_child);
+            put(&mut out, created_ms);
+            put(&mut out, node.group.edits.len() as u64);
+            for edit in &node.group.edits {
+                match &edit.kind {
+                    EditKind::Insert { idx, text } => {
+                        out.push(0);
+                        put(&mut out, *idx as u64);
+                        put_str(&mut out, text);
+                    }
+                    EditKind::Delete { idx, text } => {
+                        out.push(1);
+                        put(&mut out, *idx as u64);
+                        put_str(&mut out, text);
+                    }
+                    EditKind::Replace { idx, deleted, inserted } => {
+                        out.push(2);
+                        put(&mut out, *idx as u64);
+                        put_str(&mut out, deleted);
+                        put_str(&mut out, inserted);
+                    }
+                }
+                put(&mut out, edit.cursor_before as u64);
+                put(&mut out, edit.cursor_after as u64);
+                put_opt(&mut out, edit.anchor_before);
+                put_opt(&mut out, edit.anchor_after);
+            }
+        }
+        out
+    }
+
+    /// Parses `to_bytes` output into the tree, the saved node and the text
+    /// hash. Returns `None` for anything structurally malformed; whether the
+    /// edits fit the text is left to `replays_onto`.
+    fn from_bytes(bytes: &[u8]) -> Option<(Self, usize, u64)> {
+        let mut reader = ByteReader {
+            bytes: bytes.strip_prefix(Self::MAGIC)?,
+        };
+        let text_hash = reader.u64()?;
+        let saved = reader.usize()?;
+        let count = reader.usize()?;
+        let mut nodes = Vec::new();
+        for id in 0..count {
+            let parent = reader.usize()?;
+            let redo_child = reader.opt_usize()?;
+            let created_at = UNIX_EPOCH + Duration::from_millis(reader.u64()?);
+            let child_out_of_range = |child: usize| child <= id || child >= count;
+            if (id > 0 && parent >= id) || redo_child.is_some_and(child_out_of_range) {
+                return None;
+            }
+            let edit_count = reader.usize()?;
+            let mut edits = Vec::new();
+            for _ in 0..edit_count {
+                let tag = reader.take(1)?[0];
+                let idx = reader.usize()?;
+                let kind = match tag {
+                    0 => EditKind::Insert { idx, text: reader.string()? },
+                    1 => EditKind::Delete { idx, text: reader.string()? },
+                    2 => EditKind::Replace {
+                        idx,
+                        deleted: reader.string()?,
+                        inserted: reader.string()?,
+                    },
+                    _ => return None,
+                };
+                edits.push(Edit {
+                    kind,
+                    cursor_before: reader.usize()?,
+                    cursor_after: reader.usize()?,
+                    anchor_before: reader.opt_usize()?,
+                    anchor_after: reader.opt_usize()?,
+                });
+            }
+            nodes.push(UndoNode {
+                parent: if id == 0 { 0 } else { parent },
+                redo_child,
+                group: UndoGroup {
+                    edits,
+                    coalesce: None,
+                    updated_at: Instant::now(),
+                },
+                created_at,
+            });
+        }
+        if nodes.is_empty() || saved >= nodes.len() || !reader.bytes.is_empty() {
+            return None;
+        }
+        Some((Self::from_nodes(nodes, saved), saved, text_hash))
+    }
+
+    /// Whether every edit in the tree applies cleanly when `text` is the
+    /// state at `saved`: each range lies inside the text, each removed string
+    /// matches what is there, and each cursor and anchor is in bounds.
+    fn replays_onto(&self, saved: usize, text: &Rope) -> bool {
+        let mut root = text.clone();
+        let path = self.ancestors(saved);
+        for &id in &path[..path.len() - 1] {
+            let mut undone = self.nodes[id].group.edits.iter().rev();
+            if !undone.all(|edit| replay_edit(&mut root, edit, false)) {
+                return false;
+            }
+        }
+        // Parents come before their children, so each parent's text is ready.
+        let mut texts = vec![root];
+        for node in &self.nodes[1..] {
+            let mut text = texts[node.parent].clone();
+            if !node.group.edits.iter().all(|edit| replay_edit(&mut text, edit, true)) {
+                return false;
+            }
+            texts.push(text);
+        }
+        true
+    }
+
+    /// Nodes from `node` up to the root, inclusive.
+    fn ancestors(&self, mut node: usize) -> Vec<usize> {
+        let mut path = vec![node];
+        while node != 0 {
+            node = self.nodes[node].parent;
+            path.push(node);
+        }
+        path
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -132,32 +980,88 @@ enum EditKind {
     },
 }
 
+struct TailDecoder {
+    decoder: Decoder,
+    /// A trailing `\r` that may turn out to start a CRLF.
+    pending_cr: bool,
+}
+
 pub struct Core {
     rope: Rope,
     cursor: usize,
     selection_anchor: Option<usize>,
+    /// Display column that vertical motion tries to return to. Set by the
+    /// first up/down move and cleared by any other motion or edit.
+    goal_col: Option<usize>,
     preedit: Option<Preedit>,
-    undo: Vec<Edit>,
-    redo: Vec<Edit>,
+    undo_tree: UndoTree,
+    /// Nesting depth of `begin_group` calls; while non-zero every edit joins
+    /// the current undo group.
+    group_depth: usize,
+    group_open: bool,
+    /// Memory the undo history may hold before its oldest steps are dropped.
+    undo_budget: usize,
     path: Option<PathBuf>,
     encoding: TextEncoding,
-    dirty: bool,
+    /// Result of detection on the last load, if the text came from bytes.
+    detected_encoding: Option<DetectedEncoding>,
+    /// Byte offsets of malformed sequences replaced with U+FFFD on the last
+    /// load.
+    malformed_bytes: Vec<usize>,
+    indentation: Indentation,
+    /// Line break style written on save.
+    line_ending: LineEnding,
+    /// Whether the last load found more than one line break style.
+    mixed_line_endings: bool,
+    /// State of `append_bytes` between calls; reset by loads and encoding
+    /// changes.
+    tail_decoder: Option<TailDecoder>,
+    /// Undo tree node current when the text was last loaded or saved. The
+    /// document is dirty whenever the current node differs.
+    saved_node: Option<usize>,
+    /// `content_hash` of the text at `saved_node`.
+    saved_hash: u64,
+    /// Bumped whenever undo node ids change meaning (pruning, loading), so a
+    /// stale `SavePoint` is not mistaken for a current node.
+    undo_epoch: u64,
+}
+
+/// The undo state a save was taken from; see `Core::save_point`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SavePoint {
+    node: usize,
+    hash: u64,
+    epoch: u64,
 }
 
 impl Core {
     const PLACEHOLDER_TEXT: &'static str = "Type here...";
+    /// Longest pause between keystrokes that still merge into one undo step.
+    const COALESCE_INTERVAL: Duration = Duration::from_secs(1);
+    pub const DEFAULT_UNDO_BUDGET: usize = 64 * 1024 * 1024;
 
     pub fn new() -> Self {
         Self {
             rope: Rope::from_str(""),
             cursor: 0,
             selection_anchor: None,
+            goal_col: None,
             preedit: None,
-            undo: Vec::new(),
-            redo: Vec::new(),
+            undo_tree: UndoTree::new(),
+            group_depth: 0,
+            group_open: false,
+            undo_budget: Self::DEFAULT_UNDO_BUDGET,
             path: None,
             encoding: TextEncoding::Utf8,
-            dirty: false,
+            detected_encoding: None,
+            malformed_bytes: Vec::new(),
+            indentation: Indentation::default(),
+            line_ending: LineEnding::Lf,
+            mixed_line_endings: false,
+            tail_decoder: None,
+            saved_node: Some(0),
+            saved_hash: content_hash(b""),
+            undo_epoch: 0,
         }
     }
 
@@ -169,19 +1073,27 @@ impl Core {
         self.rope.len_lines()
     }
 
+    /// Length of `line` in chars, excluding its line break.
+    pub fn line_len(&self, line: usize) -> usize {
+        self.clamped_line_bounds(line).1
+    }
+
     pub fn display_col(&self, line: usize, col: usize) -> usize {
         if self.rope.len_chars() == 0 {
             return 0;
         }
         let max_line = self.rope.len_lines().saturating_sub(1);
         let line = line.min(max_line);
+        let line_text = self.rope.line(line).to_string();
+        let tab_width = self.indentation.tab_width;
         let mut width = 0;
-        let line_text = self.rope.line(line);
-        for ch in line_text.chars().take(col) {
-            width += match ch {
-                '\t' => 4,
-                _ => 1,
-            };
+        let mut chars = 0;
+        for grapheme in line_text.graphemes(true) {
+            chars += grapheme.chars().count();
+            if chars > col {
+                break;
+            }
+            width += grapheme_display_width(grapheme, width, tab_width);
         }
         width
     }
@@ -196,13 +1108,59 @@ impl Core {
         let line = line.min(max_line);
         let line_len = line_len_chars(&self.rope, line);
         let target_col = col.min(line_len);
-        let next = self.rope.line_to_char(line) + target_col;
+        let next = self.snap_to_grapheme(self.rope.line_to_char(line) + target_col);
         let before = self.cursor;
         let before_selection = self.selection_range();
         self.set_cursor(next, extend);
         self.cursor != before || self.selection_range() != before_selection
     }
 
+    /// Selects the run of same-class characters around (line, col), e.g. a
+    /// word, a stretch of whitespace or a run of kanji.
+    pub fn select_word_at(&mut self, line: usize, col: usize) -> bool {
+        let before = (self.cursor, self.selection_anchor);
+        self.preedit = None;
+        let (line_start, line_len) = self.clamped_line_bounds(line);
+        let offset = col.min(line_len);
+        let probe = if offset == line_len {
+            offset.saturating_sub(1)
+        } else {
+            offset
+        };
+        if line_len == 0 {
+            self.set_cursor(line_start, false);
+            return (self.cursor, self.selection_anchor) != before;
+        }
+        let class = self.class_at(line_start + probe);
+        let mut start = probe;
+        while start > 0 && self.class_at(line_start + start - 1) == class {
+            start -= 1;
+        }
+        let mut end = probe + 1;
+        while end < line_len && self.class_at(line_start + end) == class {
+            end += 1;
+        }
+        self.set_cursor(line_start + start, false);
+        self.set_cursor(line_start + end, true);
+        (self.cursor, self.selection_anchor) != before
+    }
+
+    /// Selects a whole line including its trailing line break.
+    pub fn select_line(&mut self, line: usize) -> bool {
+        let before = (self.cursor, self.selection_anchor);
+        self.preedit = None;
+        let (line_start, _) = self.clamped_line_bounds(line);
+        let line = self.rope.char_to_line(line_start);
+        let line_end = if line + 1 < self.rope.len_lines() {
+            self.rope.line_to_char(line + 1)
+        } else {
+            self.rope.len_chars()
+        };
+        self.set_cursor(line_start, false);
+        self.set_cursor(line_end, true);
+        (self.cursor, self.selection_anchor) != before
+    }
+
     pub fn display_text(&self) -> String {
         if let Some(preedit) = &self.preedit {
             let mut text = self.rope.to_string();
@@ -238,13 +1196,13 @@ impl Core {
     }
 
     pub fn ime_cursor_char(&self) -> usize {
-        if let Some(preedit) = &self.preedit {
-            if let Some((_, end)) = preedit.cursor {
-                let in_preedit = preedit.text[..end.min(preedit.text.len())]
-                    .chars()
-                    .count();
-                return self.cursor + in_preedit;
-            }
+        if let Some(preedit) = &self.preedit
+            && let Some((_, end)) = preedit.cursor
+        {
+            let in_preedit = preedit.text[..end.min(preedit.text.len())]
+                .chars()
+                .count();
+            return self.cursor + in_preedit;
         }
         self.cursor
     }
@@ -266,6 +1224,11 @@ impl Core {
         })
     }
 
+    pub fn selected_text(&self) -> Option<String> {
+        let (start, end) = self.selection_range()?;
+        Some(self.rope.slice(start..end).to_string())
+    }
+
     pub fn path(&self) -> Option<&Path> {
         self.path.as_deref()
     }
@@ -274,8 +1237,24 @@ impl Core {
         self.encoding
     }
 
+    pub fn detected_encoding(&self) -> Option<DetectedEncoding> {
+        self.detected_encoding
+    }
+
+    pub fn indentation(&self) -> Indentation {
+        self.indentation
+    }
+
+    pub fn set_indentation(&mut self, indentation: Indentation) {
+        self.indentation = Indentation {
+            tab_width: indentation.tab_width.max(1),
+            ..indentation
+        };
+        self.goal_col = None;
+    }
+
     pub fn is_dirty(&self) -> bool {
-        self.dirty
+        self.saved_node != Some(self.undo_tree.current)
     }
 
     pub fn set_preedit(&mut self, text: String, cursor: Option<(usize, usize)>) {
@@ -299,8 +1278,10 @@ impl Core {
         if text.is_empty() {
             return;
         }
+        let text = &*normalize_line_endings(text);
         self.preedit = None;
         let cursor_before = self.cursor;
+        let anchor_before = self.selection_anchor;
         let edit = if let Some((start, end)) = self.selection_range() {
             let deleted = self.remove_range(start, end);
             self.cursor = start;
@@ -315,6 +1296,8 @@ impl Core {
                 },
                 cursor_before,
                 cursor_after: self.cursor,
+                anchor_before,
+                anchor_after: None,
             }
         } else {
             self.rope.insert(self.cursor, text);
@@ -327,134 +1310,554 @@ impl Core {
                 },
                 cursor_before,
                 cursor_after: self.cursor,
+                anchor_before,
+                anchor_after: None,
             }
         };
         self.selection_anchor = None;
         self.push_undo(edit);
-        self.dirty = true;
     }
 
-    pub fn backspace(&mut self) {
+    /// Handles the Tab key: indents the selected lines when the selection
+    /// spans more than one line, otherwise inserts a tab or, with soft tabs,
+    /// spaces up to the next tab stop.
+    pub fn insert_tab(&mut self) {
+        if let Some((start, end)) = self.selection_range()
+            && self.rope.char_to_line(start) != self.rope.char_to_line(end)
+        {
+            self.indent_lines(false);
+            return;
+        }
+        let unit = if self.indentation.soft_tabs {
+            let start = self.selection_range().map_or(self.cursor, |(start, _)| start);
+            let cursor = self.cursor_for_char(start);
+            let column = self.display_col(cursor.line, cursor.col);
+            " ".repeat(grapheme_display_width("\t", column, self.indentation.tab_width))
+        } else {
+            "\t".to_string()
+        };
+        self.insert_str(&unit);
+    }
+
+    /// Adds (or with `outdent`, removes) one indentation level on every line
+    /// touched by the selection or the cursor, as a single undoable edit.
+    pub fn indent_lines(&mut self, outdent: bool) {
         self.preedit = None;
-        let cursor_before = self.cursor;
-        let edit = if let Some((start, end)) = self.selection_range() {
-            let deleted = self.remove_range(start, end);
-            self.cursor = start;
-            Edit {
-                kind: EditKind::Delete { idx: start, text: deleted },
-                cursor_before,
-                cursor_after: self.cursor,
+        let (sel_start, sel_end) = self.selection_range().unwrap_or((self.cursor, self.cursor));
+        let first_line = self.rope.char_to_line(sel_start);
+        let mut last_line = self.rope.char_to_line(sel_end);
+        if last_line > first_line && self.rope.line_to_char(last_line) == sel_end {
+            last_line -= 1;
+        }
+        let block_start = self.rope.line_to_char(first_line);
+        let block_end = self.rope.line_to_char(last_line) + line_len_chars(&self.rope, last_line);
+        let unit = self.indentation.unit();
+        let tab_width = self.indentation.tab_width;
+        // (line start before the edit, chars added (+) or removed (-))
+        let mut changes: Vec<(usize, isize)> = Vec::new();
+        let mut inserted = String::new();
+        for line in first_line..=last_line {
+            let line_start = self.rope.line_to_char(line);
+            let text: String = self
+                .rope
+                .line(line)
+                .chars()
+                .take(line_len_chars(&self.rope, line))
+                .collect();
+            let delta = if outdent {
+                let removed = if text.starts_with('\t') {
+                    1
+                } else {
+                    text.chars().take(tab_width).take_while(|ch| *ch == ' ').count()
+                };
+                inserted.extend(text.chars().skip(removed));
+                -(removed as isize)
+            } else if text.is_empty() {
+                0
+            } else {
+                inserted.push_str(&unit);
+                inserted.push_str(&text);
+                unit.chars().count() as isize
+            };
+            if outdent && delta == 0 {
+                inserted.push_str(&text);
             }
-        } else if self.cursor > 0 {
-            let remove_start = self.cursor - 1;
-            let deleted = self.remove_range(remove_start, self.cursor);
-            self.cursor = remove_start;
-            Edit {
-                kind: EditKind::Delete {
-                    idx: remove_start,
-                    text: deleted,
-                },
-                cursor_before,
-                cursor_after: self.cursor,
+            if line < last_line {
+                inserted.push('\n');
             }
-        } else {
+            changes.push((line_start, delta));
+        }
+        if changes.iter().all(|(_, delta)| *delta == 0) {
             return;
+        }
+        let map = |pos: usize| -> usize {
+            let mut shifted = pos as isize;
+            for &(line_start, delta) in &changes {
+                if pos < line_start {
+                    break;
+                }
+                let col = (pos - line_start) as isize;
+                shifted += if delta < 0 {
+                    delta.max(-col)
+                } else if col > 0 || pos != sel_start {
+                    delta
+                } else {
+                    0
+                };
+            }
+            shifted.max(0) as usize
         };
-        self.selection_anchor = None;
-        self.push_undo(edit);
-        self.dirty = true;
+        let anchor_before = self.selection_anchor;
+        let cursor_before = self.cursor;
+        let cursor_after = map(self.cursor);
+        let anchor_after = anchor_before.map(map).filter(|anchor| *anchor != cursor_after);
+        let deleted = self.remove_range(block_start, block_end);
+        self.rope.insert(block_start, &inserted);
+        self.cursor = cursor_after;
+        self.selection_anchor = anchor_after;
+        self.push_undo(Edit {
+            kind: EditKind::Replace {
+                idx: block_start,
+                deleted,
+                inserted,
+            },
+            cursor_before,
+            cursor_after,
+            anchor_before,
+            anchor_after,
+        });
     }
 
-    pub fn move_left(&mut self, extend: bool) {
-        if self.cursor == 0 {
-            return;
-        }
-        let next = self.cursor - 1;
-        self.set_cursor(next, extend);
+    pub fn backspace(&mut self) {
+        let start = self.prev_grapheme_boundary(self.cursor);
+        self.delete_selection_or(start, self.cursor);
     }
 
-    pub fn move_right(&mut self, extend: bool) {
-        if self.cursor >= self.rope.len_chars() {
+    pub fn delete_forward(&mut self) {
+        let end = self.next_grapheme_boundary(self.cursor);
+        self.delete_selection_or(self.cursor, end);
+    }
+
+    /// Deletes back to the previous word boundary (Option+Backspace).
+    pub fn delete_word_backward(&mut self) {
+        let start = self.prev_word_boundary(self.cursor);
+        self.delete_selection_or(start, self.cursor);
+    }
+
+    /// Deletes back to the start of the line (Cmd+Backspace). At the start
+    /// of a line this joins it with the previous one.
+    pub fn delete_to_line_start(&mut self) {
+        let line = self.rope.char_to_line(self.cursor);
+        let line_start = self.rope.line_to_char(line);
+        let start = if line_start == self.cursor {
+            self.prev_grapheme_boundary(self.cursor)
+        } else {
+            line_start
+        };
+        self.delete_selection_or(start, self.cursor);
+    }
+
+    /// Deletes to the end of the line (Ctrl+K). At the end of a line this
+    /// removes the line break instead.
+    pub fn kill_to_line_end(&mut self) {
+        let line = self.rope.char_to_line(self.cursor);
+        let line_end = self.rope.line_to_char(line) + line_len_chars(&self.rope, line);
+        let end = if line_end == self.cursor {
+            self.next_grapheme_boundary(self.cursor)
+        } else {
+            line_end
+        };
+        self.delete_selection_or(self.cursor, end);
+    }
+
+    /// Removes the selection as a single delete edit and returns its text.
+    pub fn cut(&mut self) -> Option<String> {
+        let (start, end) = self.selection_range()?;
+        self.delete_range(start, end)
+    }
+
+    pub fn move_left(&mut self, extend: bool) {
+        if self.cursor == 0 {
             return;
         }
-        let next = self.cursor + 1;
+        let next = self.prev_grapheme_boundary(self.cursor);
         self.set_cursor(next, extend);
     }
 
-    pub fn move_up(&mut self, extend: bool) {
-        let cursor = self.cursor_for_char(self.cursor);
-        if cursor.line == 0 {
+    pub fn move_right(&mut self, extend: bool) {
+        if self.cursor >= self.rope.len_chars() {
             return;
         }
-        let target_line = cursor.line - 1;
-        let target_line_len = line_len_chars(&self.rope, target_line);
-        let target_col = cursor.col.min(target_line_len);
-        let next = self.rope.line_to_char(target_line) + target_col;
+        let next = self.next_grapheme_boundary(self.cursor);
         self.set_cursor(next, extend);
     }
 
+    pub fn move_up(&mut self, extend: bool) {
+        self.move_vertical(-1, extend);
+    }
+
     pub fn move_down(&mut self, extend: bool) {
-        let cursor = self.cursor_for_char(self.cursor);
-        if cursor.line + 1 >= self.rope.len_lines() {
-            return;
-        }
-        let target_line = cursor.line + 1;
-        let target_line_len = line_len_chars(&self.rope, target_line);
-        let target_col = cursor.col.min(target_line_len);
-        let next = self.rope.line_to_char(target_line) + target_col;
+        self.move_vertical(1, extend);
+    }
+
+    pub fn move_page_up(&mut self, page_lines: usize, extend: bool) {
+        self.move_vertical(-(page_lines.max(1) as isize), extend);
+    }
+
+    pub fn move_page_down(&mut self, page_lines: usize, extend: bool) {
+        self.move_vertical(page_lines.max(1) as isize, extend);
+    }
+
+    pub fn move_word_left(&mut self, extend: bool) {
+        let next = self.prev_word_boundary(self.cursor);
+        self.set_cursor(next, extend);
+    }
+
+    pub fn move_word_right(&mut self, extend: bool) {
+        let next = self.next_word_boundary(self.cursor);
         self.set_cursor(next, extend);
     }
 
+    pub fn move_line_start(&mut self, extend: bool) {
+        let line = self.rope.char_to_line(self.cursor);
+        let next = self.rope.line_to_char(line);
+        self.set_cursor(next, extend);
+    }
+
+    pub fn move_line_end(&mut self, extend: bool) {
+        let line = self.rope.char_to_line(self.cursor);
+        let next = self.rope.line_to_char(line) + line_len_chars(&self.rope, line);
+        self.set_cursor(next, extend);
+    }
+
+    pub fn move_document_start(&mut self, extend: bool) {
+        self.set_cursor(0, extend);
+    }
+
+    pub fn move_document_end(&mut self, extend: bool) {
+        self.set_cursor(self.rope.len_chars(), extend);
+    }
+
     pub fn undo(&mut self) -> bool {
-        let edit = match self.undo.pop() {
-            Some(edit) => edit,
-            None => return false,
-        };
-        self.apply_edit(&edit, false);
-        self.redo.push(edit);
-        self.dirty = true;
+        self.seal_undo_group();
+        let node = self.undo_tree.current;
+        if node == 0 {
+            return false;
+        }
+        self.apply_undo_node(node, false);
+        let parent = self.undo_tree.nodes[node].parent;
+        self.undo_tree.nodes[parent].redo_child = Some(node);
+        self.undo_tree.current = parent;
+        self.seal_undo_group();
+        // An open group belongs to the node just left; later edits in the
+        // same group must start a new node rather than join `parent`.
+        self.group_open = false;
         true
     }
 
     pub fn redo(&mut self) -> bool {
-        let edit = match self.redo.pop() {
-            Some(edit) => edit,
-            None => return false,
+        self.seal_undo_group();
+        let Some(child) = self.undo_tree.nodes[self.undo_tree.current].redo_child else {
+            return false;
         };
-        self.apply_edit(&edit, true);
-        self.undo.push(edit);
-        self.dirty = true;
+        self.apply_undo_node(child, true);
+        self.undo_tree.current = child;
+        self.group_open = false;
+        true
+    }
+
+    /// Steps back to the state created just before the current one,
+    /// crossing branches if needed (like Vim's `g-`).
+    pub fn undo_earlier(&mut self) -> bool {
+        match self.undo_tree.current {
+            0 => false,
+            current => self.goto_undo_state(current - 1),
+        }
+    }
+
+    /// Steps forward to the state created just after the current one
+    /// (like Vim's `g+`).
+    pub fn undo_later(&mut self) -> bool {
+        self.goto_undo_state(self.undo_tree.current + 1)
+    }
+
+    /// Undoes and redoes along the tree until the text matches undo state
+    /// `target`. Returns `false` if there is no such state.
+    pub fn goto_undo_state(&mut self, target: usize) -> bool {
+        if target >= self.undo_tree.nodes.len() || target == self.undo_tree.current {
+            return false;
+        }
+        let target_path = self.undo_tree.ancestors(target);
+        while !target_path.contains(&self.undo_tree.current) {
+            self.undo();
+        }
+        let common = target_path
+            .iter()
+            .position(|node| *node == self.undo_tree.current)
+            .unwrap_or(0);
+        for &node in target_path[..common].iter().rev() {
+            self.undo_tree.nodes[self.undo_tree.current].redo_child = Some(node);
+            self.redo();
+        }
         true
     }
 
-    pub fn load_from_bytes(&mut self, bytes: &[u8]) -> Result<TextEncoding, CoreError> {
-        let (encoding, bom_len) = Encoding::for_bom(bytes).unwrap_or((UTF_8, 0));
-        let encoding = TextEncoding::from_encoding(encoding).unwrap_or(TextEncoding::Utf8);
-        let payload = &bytes[bom_len..];
-        let (decoded, _, _) = encoding.encoding().decode(payload);
-        self.rope = Rope::from_str(decoded.as_ref());
+    pub fn undo_stats(&self) -> UndoStats {
+        UndoStats {
+            entries: self.undo_tree.nodes.len() - 1,
+            bytes: self.undo_tree.bytes,
+        }
+    }
+
+    /// Caps the memory held by undo history; the oldest steps are dropped
+    /// once it is exceeded. The most recent step is always kept.
+    pub fn set_undo_budget(&mut self, bytes: usize) {
+        self.undo_budget = bytes;
+        if let Some(remap) = self.undo_tree.prune_to(bytes) {
+            self.saved_node = self.saved_node.and_then(|saved| remap[saved]);
+            self.undo_epoch += 1;
+        }
+    }
+
+    /// Leaves of the undo tree, oldest first.
+    pub fn undo_branches(&self) -> Vec<UndoBranch> {
+        let tree = &self.undo_tree;
+        let mut has_children = vec![false; tree.nodes.len()];
+        for node in tree.nodes.iter().skip(1) {
+            has_children[node.parent] = true;
+        }
+        (1..tree.nodes.len())
+            .filter(|id| !has_children[*id])
+            .map(|id| UndoBranch {
+                id,
+                changes: tree.depth(id),
+                created_at: tree.nodes[id].created_at,
+                current: id == tree.current,
+            })
+            .collect()
+    }
+
+    /// Starts a compound operation: every edit until the matching
+    /// `end_group` undoes and redoes as a single step. Groups nest.
+    pub fn begin_group(&mut self) {
+        if self.group_depth == 0 {
+            self.seal_undo_group();
+        }
+        self.group_depth += 1;
+    }
+
+    pub fn end_group(&mut self) {
+        self.group_depth = self.group_depth.saturating_sub(1);
+        if self.group_depth == 0 {
+            self.group_open = false;
+        }
+    }
+
+    /// Loads `bytes` in the detected encoding. Malformed sequences are
+    /// replaced rather than refused; `decode_error` reports them.
+    pub fn load_from_bytes(&mut self, bytes: &[u8]) -> DetectedEncoding {
+        self.load_detected(bytes, detect_encoding(bytes))
+    }
+
+    /// Loads `bytes` decoded as `encoding` instead of the detected encoding,
+    /// for reopening a file whose guess was wrong. A BOM is skipped only if
+    /// it belongs to `encoding`; UTF-8 with a BOM is kept as `Utf8Bom`.
+    pub fn load_from_bytes_as(&mut self, bytes: &[u8], encoding: TextEncoding) -> DetectedEncoding {
+        let encoding = if encoding == TextEncoding::Utf8
+            && bytes.starts_with(TextEncoding::Utf8Bom.bom())
+        {
+            TextEncoding::Utf8Bom
+        } else {
+            encoding
+        };
+        let bom = encoding.bom();
+        let bom_len = if !bom.is_empty() && bytes.starts_with(bom) {
+            bom.len()
+        } else {
+            0
+        };
+        self.load_detected(
+            bytes,
+            DetectedEncoding {
+                encoding,
+                bom_len,
+                confidence: 1.0,
+            },
+        )
+    }
+
+    fn load_detected(&mut self, bytes: &[u8], detected: DetectedEncoding) -> DetectedEncoding {
+        let encoding = detected.encoding;
+        let payload = &bytes[detected.bom_len..];
+        let (decoded, malformed) = decode_reporting(encoding, payload);
+        let (line_ending, mixed) = detect_line_ending(&decoded);
+        self.line_ending = line_ending;
+        self.mixed_line_endings = mixed;
+        self.rope = Rope::from_str(&normalize_line_endings(&decoded));
+        self.malformed_bytes = malformed
+            .into_iter()
+            .map(|offset| offset + detected.bom_len)
+            .collect();
         self.cursor = 0;
         self.selection_anchor = None;
+        self.goal_col = None;
         self.preedit = None;
-        self.undo.clear();
-        self.redo.clear();
+        self.undo_tree = UndoTree::new();
+        self.undo_epoch += 1;
+        self.group_open = false;
+        self.group_depth = 0;
         self.encoding = encoding;
-        self.dirty = false;
-        Ok(encoding)
+        self.detected_encoding = Some(detected);
+        self.tail_decoder = None;
+        self.saved_node = Some(0);
+        self.saved_hash = self.text_hash();
+        detected
+    }
+
+    /// The decode problems of the last load as a `SystemErrorKind::Encoding`
+    /// error, or `None` if the bytes decoded cleanly.
+    pub fn decode_error(&self) -> Option<CoreError> {
+        let first = *self.malformed_bytes.first()?;
+        Some(CoreError::System(SystemError {
+            kind: SystemErrorKind::Encoding,
+            context: format!(
+                "{} malformed {} sequence(s) replaced with U+FFFD, first at byte {}",
+                self.malformed_bytes.len(),
+                self.encoding.label(),
+                first
+            ),
+            retriable: false,
+            positions: self.malformed_bytes.clone(),
+        }))
+    }
+
+    /// Checks that the whole text can be saved as `encoding` without loss.
+    /// The error lists the char index of every character that cannot.
+    pub fn check_encodable(&self, encoding: TextEncoding) -> Result<(), CoreError> {
+        let mut positions = Vec::new();
+        let mut offset = 0;
+        for chunk in self.rope.chunks() {
+            positions.extend(
+                unencodable_chars(chunk, encoding)
+                    .into_iter()
+                    .map(|idx| idx + offset),
+            );
+            offset += chunk.chars().count();
+        }
+        let Some(&first) = positions.first() else {
+            return Ok(());
+        };
+        let cursor = self.cursor_for_char(first);
+        Err(CoreError::Domain(DomainError {
+            kind: DomainErrorKind::Unencodable,
+            context: format!(
+                "{} character(s) cannot be saved as {}, first at line {}, col {}",
+                positions.len(),
+                encoding.label(),
+                cursor.line + 1,
+                cursor.col + 1
+            ),
+            positions,
+        }))
+    }
+
+    /// Char columns on `line` to flag to the user: characters the current
+    /// encoding cannot represent and U+FFFD left by a lossy decode.
+    pub fn encoding_issue_cols(&self, line: usize) -> Vec<usize> {
+        if line >= self.rope.len_lines() {
+            return Vec::new();
+        }
+        let text = self.rope.line(line).to_string();
+        let mut cols = unencodable_chars(&text, self.encoding);
+        cols.extend(
+            text.chars()
+                .enumerate()
+                .filter(|(_, ch)| *ch == char::REPLACEMENT_CHARACTER)
+                .map(|(col, _)| col),
+        );
+        cols.sort_unstable();
+        cols.dedup();
+        cols
     }
 
-    pub fn encode_text(text: &str, encoding: TextEncoding) -> Vec<u8> {
+    /// Encodes `text` for saving, writing each `\n` as `line_ending`.
+    /// Characters `encoding` cannot represent are written as numeric
+    /// character references; call `check_encodable` first to catch that.
+    pub fn encode_text(text: &str, encoding: TextEncoding, line_ending: LineEnding) -> Vec<u8> {
+        let converted;
+        let text = if line_ending == LineEnding::Lf {
+            text
+        } else {
+            converted = text.replace('\n', line_ending.as_str());
+            &converted
+        };
         let mut output = Vec::new();
         output.extend_from_slice(encoding.bom());
-        let (encoded, _, _) = encoding.encoding().encode(text);
-        output.extend_from_slice(encoded.as_ref());
+        match encoding {
+            // encoding_rs only decodes UTF-16; its encoders emit UTF-8.
+            TextEncoding::Utf16Le => {
+                output.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+            }
+            TextEncoding::Utf16Be => {
+                output.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+            }
+            _ => {
+                let (encoded, _, _) = encoding.encoding().encode(text);
+                output.extend_from_slice(encoded.as_ref());
+            }
+        }
         output
     }
 
-    pub fn mark_saved(&mut self, path: PathBuf, encoding: TextEncoding) {
+    /// Snapshot of the undo state the text is about to be saved from. Later
+    /// edits start a new undo step, so they never count as saved.
+    pub fn save_point(&mut self) -> SavePoint {
+        self.seal_undo_group();
+        self.group_open = false;
+        SavePoint {
+            node: self.undo_tree.current,
+            hash: self.text_hash(),
+            epoch: self.undo_epoch,
+        }
+    }
+
+    /// Records a finished save of the text as it was at `point`. Edits made
+    /// while the save was running leave the document dirty.
+    pub fn mark_saved_at(&mut self, path: PathBuf, encoding: TextEncoding, point: SavePoint) {
         self.path = Some(path);
         self.encoding = encoding;
-        self.dirty = false;
+        // Pruning renumbers the nodes, so a point from before it can't be found.
+        self.saved_node = (point.epoch == self.undo_epoch).then_some(point.node);
+        self.saved_hash = point.hash;
+    }
+
+    /// Serialized undo tree for the persistent undo store, anchored at the
+    /// state last loaded or saved. `None` once that state has been pruned,
+    /// since the history could no longer be matched to the file.
+    pub fn undo_history_bytes(&self) -> Option<Vec<u8>> {
+        let saved = self.saved_node?;
+        Some(self.undo_tree.to_bytes(saved, self.saved_hash))
+    }
+
+    /// Replaces a freshly loaded document's empty history with one written by
+    /// `undo_history_bytes`. Does nothing unless the history was recorded
+    /// against exactly the current text.
+    pub fn restore_undo_history(&mut self, bytes: &[u8]) -> bool {
+        if self.undo_tree.nodes.len() > 1 || self.is_dirty() {
+            return false;
+        }
+        let Some((tree, saved, text_hash)) = UndoTree::from_bytes(bytes) else {
+            return false;
+        };
+        // The hash alone could let a damaged file through, and a bad edit
+        // would panic on the next undo or redo.
+        if text_hash != self.saved_hash || !tree.replays_onto(saved, &self.rope) {
+            return false;
+        }
+        self.undo_tree = tree;
+        self.undo_epoch += 1;
+        self.saved_node = Some(saved);
+        true
     }
 
     pub fn set_path(&mut self, path: Option<PathBuf>) {
@@ -463,14 +1866,335 @@ impl Core {
 
     pub fn set_encoding(&mut self, encoding: TextEncoding) {
         self.encoding = encoding;
+        self.tail_decoder = None;
+    }
+
+    /// Appends bytes that were added to the end of the file, decoded in the
+    /// document's encoding, for following a growing log. This is not an
+    /// undoable edit: a clean document stays clean. A character or CRLF
+    /// split between two calls is held back until the rest arrives.
+    pub fn append_bytes(&mut self, bytes: &[u8]) {
+        let encoding = self.encoding.encoding();
+        let tail = self.tail_decoder.get_or_insert_with(|| TailDecoder {
+            decoder: encoding.new_decoder_without_bom_handling(),
+            pending_cr: false,
+        });
+        let mut decoded = String::new();
+        if std::mem::take(&mut tail.pending_cr) {
+            decoded.push('\r');
+        }
+        let mut read = 0;
+        loop {
+            let remaining = bytes.len() - read;
+            decoded.reserve(
+                tail.decoder
+                    .max_utf8_buffer_length(remaining)
+                    .unwrap_or(remaining * 3 + 16),
+            );
+            let (result, consumed, _) =
+                tail.decoder.decode_to_string(&bytes[read..], &mut decoded, false);
+            read += consumed;
+            if result == CoderResult::InputEmpty {
+                break;
+            }
+        }
+        if decoded.ends_with('\r') {
+            decoded.pop();
+            tail.pending_cr = true;
+        }
+        if decoded.is_empty() {
+            return;
+        }
+        let was_clean = !self.is_dirty();
+        self.rope.insert(self.rope.len_chars(), &normalize_line_endings(&decoded));
+        if was_clean {
+            self.mark_clean();
+        }
+    }
+
+    pub fn line_ending(&self) -> LineEnding {
+        self.line_ending
+    }
+
+    /// Whether the file had more than one line break style when loaded. They
+    /// are all written as `line_ending` on save.
+    pub fn has_mixed_line_endings(&self) -> bool {
+        self.mixed_line_endings
+    }
+
+    /// Converts every line break to `line_ending` on the next save. The file
+    /// on disk no longer matches, so no undo state counts as saved until then.
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        if line_ending == self.line_ending && !self.mixed_line_endings {
+            return;
+        }
+        self.line_ending = line_ending;
+        self.mixed_line_endings = false;
+        self.saved_node = None;
+    }
+
+    /// Records the current undo position as the saved state.
+    fn mark_clean(&mut self) {
+        self.seal_undo_group();
+        self.group_open = false;
+        self.saved_node = Some(self.undo_tree.current);
+        self.saved_hash = self.text_hash();
+    }
+
+    fn text_hash(&self) -> u64 {
+        let mut hasher = ContentHasher::default();
+        for chunk in self.rope.chunks() {
+            hasher.write(chunk.as_bytes());
+        }
+        hasher.finish()
     }
 
     fn push_undo(&mut self, edit: Edit) {
-        self.undo.push(edit);
-        self.redo.clear();
+        self.push_undo_at(edit, Instant::now());
+        if let Some(remap) = self.undo_tree.prune_to(self.undo_budget) {
+            self.saved_node = self.saved_node.and_then(|saved| remap[saved]);
+            self.undo_epoch += 1;
+        }
+    }
+
+    fn push_undo_at(&mut self, edit: Edit, now: Instant) {
+        self.goal_col = None;
+        let size = edit.bytes();
+        if self.group_depth > 0 {
+            if self.group_open
+                && let Some(group) = self.undo_tree.current_group_mut()
+            {
+                group.edits.push(edit);
+                self.undo_tree.bytes += size;
+            } else {
+                self.group_open = true;
+                self.undo_tree.push(UndoGroup {
+                    edits: vec![edit],
+                    coalesce: None,
+                    updated_at: now,
+                });
+            }
+            return;
+        }
+        let coalesce = coalesce_kind(&edit);
+        if let Some(kind) = coalesce
+            && let Some(group) = self.undo_tree.current_group_mut()
+            && group.coalesce == Some(kind)
+            && now.duration_since(group.updated_at) <= Self::COALESCE_INTERVAL
+            && let Some(last) = group.edits.last()
+            && continues_edit(last, &edit)
+        {
+            group.edits.push(edit);
+            group.updated_at = now;
+            self.undo_tree.bytes += size;
+            return;
+        }
+        self.undo_tree.push(UndoGroup {
+            edits: vec![edit],
+            coalesce,
+            updated_at: now,
+        });
+    }
+
+    /// Stops the current undo group from absorbing later edits.
+    fn seal_undo_group(&mut self) {
+        if let Some(group) = self.undo_tree.current_group_mut() {
+            group.coalesce = None;
+        }
+    }
+
+    fn apply_undo_node(&mut self, node: usize, forward: bool) {
+        let edits = std::mem::take(&mut self.undo_tree.nodes[node].group.edits);
+        if forward {
+            for edit in &edits {
+                self.apply_edit(edit, true);
+            }
+        } else {
+            for edit in edits.iter().rev() {
+                self.apply_edit(edit, false);
+            }
+        }
+        self.undo_tree.nodes[node].group.edits = edits;
+    }
+
+    fn move_vertical(&mut self, delta: isize, extend: bool) {
+        let cursor = self.cursor_for_char(self.cursor);
+        let max_line = self.rope.len_lines().saturating_sub(1);
+        let target_line = cursor.line.saturating_add_signed(delta).min(max_line);
+        if target_line == cursor.line {
+            return;
+        }
+        let goal = self
+            .goal_col
+            .unwrap_or_else(|| self.display_col(cursor.line, cursor.col));
+        let target_col = self.col_for_display(target_line, goal);
+        let next = self.rope.line_to_char(target_line) + target_col;
+        self.set_cursor(next, extend);
+        self.goal_col = Some(goal);
+    }
+
+    /// Inverse of `display_col`: the char column on `line` whose display
+    /// position is nearest to `display`, rounding ties to the left.
+    fn col_for_display(&self, line: usize, display: usize) -> usize {
+        let line_len = line_len_chars(&self.rope, line);
+        let text = self.rope.line(line).to_string();
+        let mut width = 0;
+        let mut col = 0;
+        for grapheme in text.graphemes(true) {
+            let chars = grapheme.chars().count();
+            if col + chars > line_len {
+                break;
+            }
+            let next =
+                width + grapheme_display_width(grapheme, width, self.indentation.tab_width);
+            if next > display {
+                return if display - width > next - display { col + chars } else { col };
+            }
+            width = next;
+            col += chars;
+        }
+        line_len
+    }
+
+    /// Char index of the extended grapheme cluster boundary before `idx`.
+    fn prev_grapheme_boundary(&self, idx: usize) -> usize {
+        if idx == 0 {
+            return 0;
+        }
+        let line = self.rope.char_to_line(idx - 1);
+        let line_start = self.rope.line_to_char(line);
+        let text = self.rope.line(line).to_string();
+        let byte = char_to_byte_idx(&text, idx - line_start);
+        let mut cursor = GraphemeCursor::new(byte, text.len(), true);
+        match cursor.prev_boundary(&text, 0) {
+            Ok(Some(prev)) => line_start + text[..prev].chars().count(),
+            _ => idx - 1,
+        }
+    }
+
+    /// Char index of the extended grapheme cluster boundary after `idx`.
+    fn next_grapheme_boundary(&self, idx: usize) -> usize {
+        let len = self.rope.len_chars();
+        if idx >= len {
+            return len;
+        }
+        let line = self.rope.char_to_line(idx);
+        let line_start = self.rope.line_to_char(line);
+        let text = self.rope.line(line).to_string();
+        let byte = char_to_byte_idx(&text, idx - line_start);
+        let mut cursor = GraphemeCursor::new(byte, text.len(), true);
+        match cursor.next_boundary(&text, 0) {
+            Ok(Some(next)) => line_start + text[..next].chars().count(),
+            _ => idx + 1,
+        }
+    }
+
+    /// Moves `idx` back to the start of the grapheme cluster it falls in.
+    fn snap_to_grapheme(&self, idx: usize) -> usize {
+        if idx == 0 || idx >= self.rope.len_chars() {
+            return idx;
+        }
+        let line = self.rope.char_to_line(idx);
+        let line_start = self.rope.line_to_char(line);
+        let text = self.rope.line(line).to_string();
+        let byte = char_to_byte_idx(&text, idx - line_start);
+        let mut cursor = GraphemeCursor::new(byte, text.len(), true);
+        if cursor.is_boundary(&text, 0).unwrap_or(true) {
+            return idx;
+        }
+        match cursor.prev_boundary(&text, 0) {
+            Ok(Some(prev)) => line_start + text[..prev].chars().count(),
+            _ => idx,
+        }
+    }
+
+    fn delete_selection_or(&mut self, start: usize, end: usize) {
+        let (start, end) = self.selection_range().unwrap_or((start, end));
+        self.delete_range(start, end);
+    }
+
+    fn delete_range(&mut self, start: usize, end: usize) -> Option<String> {
+        self.preedit = None;
+        if start >= end {
+            return None;
+        }
+        let cursor_before = self.cursor;
+        let anchor_before = self.selection_anchor;
+        let deleted = self.remove_range(start, end);
+        self.cursor = start;
+        self.selection_anchor = None;
+        self.push_undo(Edit {
+            kind: EditKind::Delete {
+                idx: start,
+                text: deleted.clone(),
+            },
+            cursor_before,
+            cursor_after: self.cursor,
+            anchor_before,
+            anchor_after: None,
+        });
+        Some(deleted)
+    }
+
+    /// Start of the word before `idx`, skipping whitespace first. A line
+    /// break directly before `idx` counts as a word of its own.
+    fn prev_word_boundary(&self, idx: usize) -> usize {
+        let mut i = idx;
+        while i > 0 && self.class_at(i - 1) == CharClass::Whitespace {
+            i -= 1;
+        }
+        if i == 0 {
+            return 0;
+        }
+        let class = self.class_at(i - 1);
+        if class == CharClass::LineBreak {
+            return if i == idx { i - 1 } else { i };
+        }
+        while i > 0 && self.class_at(i - 1) == class {
+            i -= 1;
+        }
+        i
+    }
+
+    /// End of the word after `idx`, mirroring `prev_word_boundary`.
+    fn next_word_boundary(&self, idx: usize) -> usize {
+        let len = self.rope.len_chars();
+        let mut i = idx;
+        while i < len && self.class_at(i) == CharClass::Whitespace {
+            i += 1;
+        }
+        if i == len {
+            return len;
+        }
+        let class = self.class_at(i);
+        if class == CharClass::LineBreak {
+            return if i == idx { i + 1 } else { i };
+        }
+        while i < len && self.class_at(i) == class {
+            i += 1;
+        }
+        i
+    }
+
+    /// Class of the char at `idx`; combining marks take the class of the
+    /// char they attach to.
+    fn class_at(&self, idx: usize) -> CharClass {
+        let mut idx = idx;
+        while idx > 0 && is_combining_mark(self.rope.char(idx)) {
+            idx -= 1;
+        }
+        char_class(self.rope.char(idx))
+    }
+
+    fn clamped_line_bounds(&self, line: usize) -> (usize, usize) {
+        let max_line = self.rope.len_lines().saturating_sub(1);
+        let line = line.min(max_line);
+        (self.rope.line_to_char(line), line_len_chars(&self.rope, line))
     }
 
     fn set_cursor(&mut self, next: usize, extend: bool) {
+        self.goal_col = None;
+        self.seal_undo_group();
         if extend {
             if self.selection_anchor.is_none() {
                 self.selection_anchor = Some(self.cursor);
@@ -479,10 +2203,8 @@ impl Core {
             self.selection_anchor = None;
         }
         self.cursor = next.min(self.rope.len_chars());
-        if let Some(anchor) = self.selection_anchor {
-            if anchor == self.cursor {
-                self.selection_anchor = None;
-            }
+        if self.selection_anchor == Some(self.cursor) {
+            self.selection_anchor = None;
         }
     }
 
@@ -497,7 +2219,7 @@ impl Core {
 
     fn apply_edit(&mut self, edit: &Edit, forward: bool) {
         self.preedit = None;
-        self.selection_anchor = None;
+        self.goal_col = None;
         match (&edit.kind, forward) {
             (EditKind::Insert { idx, text }, true) => {
                 self.rope.insert(*idx, text);
@@ -524,11 +2246,106 @@ impl Core {
                 self.rope.insert(*idx, deleted);
             }
         }
-        self.cursor = if forward {
-            edit.cursor_after
+        (self.cursor, self.selection_anchor) = if forward {
+            (edit.cursor_after, edit.anchor_after)
         } else {
-            edit.cursor_before
+            (edit.cursor_before, edit.anchor_before)
+        };
+    }
+}
+
+/// Applies `edit` to `rope` like `Core::apply_edit`, but returns `false`
+/// instead of panicking if it does not fit the text.
+fn replay_edit(rope: &mut Rope, edit: &Edit, forward: bool) -> bool {
+    let (cursor_before, anchor_before, cursor_after, anchor_after) = if forward {
+        (edit.cursor_before, edit.anchor_before, edit.cursor_after, edit.anchor_after)
+    } else {
+        (edit.cursor_after, edit.anchor_after, edit.cursor_before, edit.anchor_before)
+    };
+    let in_bounds = |rope: &Rope, cursor: usize, anchor: Option<usize>| {
+        let len = rope.len_chars();
+        cursor <= len && anchor.is_none_or(|anchor| anchor <= len)
+    };
+    let remove = |rope: &mut Rope, idx: usize, text: &str| {
+        let Some(end) = idx.checked_add(text.chars().count()) else {
+            return false;
         };
+        if end > rope.len_chars() || rope.slice(idx..end) != text {
+            return false;
+        }
+        rope.remove(idx..end);
+        true
+    };
+    let insert = |rope: &mut Rope, idx: usize, text: &str| {
+        if idx > rope.len_chars() {
+            return false;
+        }
+        rope.insert(idx, text);
+        true
+    };
+    if !in_bounds(rope, cursor_before, anchor_before) {
+        return false;
+    }
+    let applied = match (&edit.kind, forward) {
+        (EditKind::Insert { idx, text }, true) | (EditKind::Delete { idx, text }, false) => {
+            insert(rope, *idx, text)
+        }
+        (EditKind::Insert { idx, text }, false) | (EditKind::Delete { idx, text }, true) => {
+            remove(rope, *idx, text)
+        }
+        (EditKind::Replace { idx, deleted, inserted }, true) => {
+            remove(rope, *idx, deleted) && insert(rope, *idx, inserted)
+        }
+        (EditKind::Replace { idx, deleted, inserted }, false) => {
+            remove(rope, *idx, inserted) && insert(rope, *idx, deleted)
+        }
+    };
+    applied && in_bounds(rope, cursor_after, anchor_after)
+}
+
+/// How `edit` may merge with neighbouring edits: plain typing without line
+/// breaks, or a single grapheme removed by Backspace or Delete.
+fn coalesce_kind(edit: &Edit) -> Option<Coalesce> {
+    if edit.anchor_before.is_some() {
+        return None;
+    }
+    match &edit.kind {
+        EditKind::Insert { text, .. } if !text.contains('\n') => Some(Coalesce::Typing),
+        EditKind::Delete { idx, text }
+            if !text.contains('\n') && text.graphemes(true).count() == 1 =>
+        {
+            if edit.cursor_before == *idx {
+                Some(Coalesce::DeleteForward)
+            } else {
+                Some(Coalesce::Backspace)
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Whether `next` picks up where `prev` left off without crossing into a new
+/// word, so the two undo as one step.
+fn continues_edit(prev: &Edit, next: &Edit) -> bool {
+    let starts_word = |before: Option<char>, after: Option<char>| {
+        matches!((before, after), (Some(b), Some(a)) if b.is_whitespace() && !a.is_whitespace())
+    };
+    match (&prev.kind, &next.kind) {
+        (EditKind::Insert { idx: prev_idx, text: prev_text }, EditKind::Insert { idx, text }) => {
+            *idx == prev_idx + prev_text.chars().count()
+                && !starts_word(prev_text.chars().last(), text.chars().next())
+        }
+        (EditKind::Delete { idx: prev_idx, text: prev_text }, EditKind::Delete { idx, text }) => {
+            if *idx == *prev_idx {
+                // Delete key: the next char slides into place.
+                !starts_word(prev_text.chars().last(), text.chars().next())
+            } else {
+                // Backspace: the deleted text sits right before the last one.
+                idx + text.chars().count() == *prev_idx
+                    && !starts_word(prev_text.chars().next(), text.chars().last())
+            }
+        }
+        _ => false,
     }
 }
 
@@ -552,32 +2369,218 @@ impl CoreError {
                     | std::io::ErrorKind::WouldBlock
                     | std::io::ErrorKind::TimedOut
             ),
+            positions: Vec::new(),
         })
     }
 
     pub fn describe(&self) -> String {
         match self {
             CoreError::System(err) => format!(
-                "system error: {:?} (retriable={}) {}",
-                err.kind, err.retriable, err.context
+                "system error: {:?} (retriable={}) {}{}",
+                err.kind,
+                err.retriable,
+                err.context,
+                describe_positions(&err.positions)
+            ),
+            CoreError::Domain(err) => format!(
+                "domain error: {:?} {}{}",
+                err.kind,
+                err.context,
+                describe_positions(&err.positions)
             ),
-            CoreError::Domain(err) => {
-                format!("domain error: {:?} {}", err.kind, err.context)
-            }
         }
     }
-}
 
-fn line_len_chars(rope: &Rope, line: usize) -> usize {
-    let line_text = rope.line(line);
-    let len = line_text.len_chars();
-    if line + 1 < rope.len_lines() && len > 0 {
-        len - 1
-    } else {
-        len
+    /// Positions the error refers to, if any (see `SystemError::positions`
+    /// and `DomainError::positions`).
+    pub fn positions(&self) -> &[usize] {
+        match self {
+            CoreError::System(err) => &err.positions,
+            CoreError::Domain(err) => &err.positions,
+        }
     }
 }
 
+fn describe_positions(positions: &[usize]) -> String {
+    const SHOWN: usize = 8;
+    if positions.is_empty() {
+        return String::new();
+    }
+    let mut shown = positions
+        .iter()
+        .take(SHOWN)
+        .map(usize::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    if positions.len() > SHOWN {
+        shown.push_str(", ...");
+    }
+    format!(" at [{shown}]")
+}
+
+/// 64-bit FNV-1a. Stable across builds, unlike `std`'s default hasher, so it
+/// can key data written to disk.
+#[derive(Debug, Clone, Copy)]
+pub struct ContentHasher(u64);
+
+impl Default for ContentHasher {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl ContentHasher {
+    /// Continues from a value returned by `finish`, as if the bytes hashed
+    /// so far were written again.
+    pub fn resume(hash: u64) -> Self {
+        Self(hash)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    pub fn finish(self) -> u64 {
+        self.0
+    }
+}
+
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    let mut hasher = ContentHasher::default();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// One line of a `line_diff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffLine<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Largest table `line_diff` builds; bigger changes are shown as a
+/// wholesale replacement instead.
+const DIFF_MAX_CELLS: usize = 4_000_000;
+
+/// Line-by-line changes that turn `old` into `new`: the longest common
+/// subsequence of the lines between their shared head and tail.
+pub fn line_diff<'a>(old: &'a str, new: &'a str) -> Vec<DiffLine<'a>> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+
+    let mut diff: Vec<DiffLine> = old[..prefix].iter().map(|line| DiffLine::Same(line)).collect();
+    let (mut i, mut j) = (0, 0);
+    if old_mid.len().saturating_mul(new_mid.len()) <= DIFF_MAX_CELLS {
+        // lengths[i * width + j] is the LCS length of old_mid[i..] and new_mid[j..].
+        let width = new_mid.len() + 1;
+        let mut lengths = vec![0u32; (old_mid.len() + 1) * width];
+        for i in (0..old_mid.len()).rev() {
+            for j in (0..new_mid.len()).rev() {
+                lengths[i * width + j] = if old_mid[i] == new_mid[j] {
+                    lengths[(i + 1) * width + j + 1] + 1
+                } else {
+                    lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+                };
+            }
+        }
+        while i < old_mid.len() && j < new_mid.len() {
+            if old_mid[i] == new_mid[j] {
+                diff.push(DiffLine::Same(old_mid[i]));
+                i += 1;
+                j += 1;
+            } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+                diff.push(DiffLine::Removed(old_mid[i]));
+                i += 1;
+            } else {
+                diff.push(DiffLine::Added(new_mid[j]));
+                j += 1;
+            }
+        }
+    }
+    diff.extend(old_mid[i..].iter().map(|line| DiffLine::Removed(line)));
+    diff.extend(new_mid[j..].iter().map(|line| DiffLine::Added(line)));
+    diff.extend(old[old.len() - suffix..].iter().map(|line| DiffLine::Same(line)));
+    diff
+}
+
+fn line_len_chars(rope: &Rope, line: usize) -> usize {
+    let line_text = rope.line(line);
+    let len = line_text.len_chars();
+    if line + 1 < rope.len_lines() && len > 0 {
+        len - 1
+    } else {
+        len
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CharClass {
+    Whitespace,
+    LineBreak,
+    Word,
+    Hiragana,
+    Katakana,
+    Han,
+    Punctuation,
+}
+
+fn char_class(ch: char) -> CharClass {
+    match ch {
+        '\n' | '\r' => CharClass::LineBreak,
+        _ if ch.is_whitespace() => CharClass::Whitespace,
+        '\u{3041}'..='\u{309F}' => CharClass::Hiragana,
+        '\u{30A0}'..='\u{30FF}' | '\u{31F0}'..='\u{31FF}' | '\u{FF66}'..='\u{FF9F}' => {
+            CharClass::Katakana
+        }
+        '\u{3400}'..='\u{4DBF}' | '\u{4E00}'..='\u{9FFF}' | '\u{F900}'..='\u{FAFF}' | '々' => {
+            CharClass::Han
+        }
+        _ if ch.is_alphanumeric() || ch == '_' => CharClass::Word,
+        _ => CharClass::Punctuation,
+    }
+}
+
+/// Display cells taken by one grapheme cluster that starts at display
+/// column `column`: East Asian wide and fullwidth clusters take 2, combining
+/// marks 0, and tabs run to the next multiple of `tab_width`.
+pub fn grapheme_display_width(grapheme: &str, column: usize, tab_width: usize) -> usize {
+    match grapheme {
+        "\t" => {
+            let tab_width = tab_width.max(1);
+            tab_width - column % tab_width
+        }
+        _ => grapheme.width(),
+    }
+}
+
+fn is_combining_mark(ch: char) -> bool {
+    matches!(
+        ch,
+        '\u{0300}'..='\u{036F}'
+            | '\u{1AB0}'..='\u{1AFF}'
+            | '\u{1DC0}'..='\u{1DFF}'
+            | '\u{200D}'
+            | '\u{20D0}'..='\u{20FF}'
+            | '\u{3099}'..='\u{309A}'
+            | '\u{FE00}'..='\u{FE0F}'
+            | '\u{FE20}'..='\u{FE2F}'
+            | '\u{E0100}'..='\u{E01EF}'
+    )
+}
+
 fn char_to_byte_idx(text: &str, char_idx: usize) -> usize {
     text.char_indices()
         .nth(char_idx)
@@ -588,6 +2591,7 @@ fn char_to_byte_idx(text: &str, char_idx: usize) -> usize {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use encoding_rs::{EUC_JP, ISO_2022_JP, SHIFT_JIS, WINDOWS_1252};
 
     #[test]
     fn cursor_for_char_clamps_empty_rope() {
@@ -635,7 +2639,978 @@ mod tests {
         let mut core = Core::new();
         core.insert_str("a\tb");
         assert_eq!(core.display_col(0, 1), 1);
-        assert_eq!(core.display_col(0, 2), 5);
-        assert_eq!(core.display_col(0, 3), 6);
+        assert_eq!(core.display_col(0, 2), 4);
+        assert_eq!(core.display_col(0, 3), 5);
+    }
+
+    #[test]
+    fn display_col_follows_configured_tab_width() {
+        let mut core = Core::new();
+        core.insert_str("\tab\tc");
+        core.set_indentation(Indentation {
+            tab_width: 8,
+            soft_tabs: false,
+        });
+        assert_eq!(core.display_col(0, 1), 8);
+        assert_eq!(core.display_col(0, 4), 16);
+    }
+
+    #[test]
+    fn insert_tab_uses_soft_tabs_to_next_stop() {
+        let mut core = Core::new();
+        core.set_indentation(Indentation {
+            tab_width: 4,
+            soft_tabs: true,
+        });
+        core.insert_str("ab");
+        core.insert_tab();
+        assert_eq!(core.text(), "ab  ");
+        core.insert_tab();
+        assert_eq!(core.text(), "ab      ");
+    }
+
+    #[test]
+    fn indent_and_outdent_selected_lines_as_one_edit() {
+        let mut core = Core::new();
+        core.insert_str("a\n\nb\nc");
+        core.set_cursor_line_col(0, 0, false);
+        core.set_cursor_line_col(2, 1, true);
+        core.insert_tab();
+        assert_eq!(core.text(), "\ta\n\n\tb\nc");
+        assert_eq!(core.selected_text().as_deref(), Some("\ta\n\n\tb"));
+        core.indent_lines(true);
+        assert_eq!(core.text(), "a\n\nb\nc");
+        core.indent_lines(true);
+        assert_eq!(core.text(), "a\n\nb\nc");
+        assert!(core.undo());
+        assert_eq!(core.text(), "\ta\n\n\tb\nc");
+        assert!(core.undo());
+        assert_eq!(core.text(), "a\n\nb\nc");
+    }
+
+    #[test]
+    fn outdent_removes_up_to_one_level_of_spaces() {
+        let mut core = Core::new();
+        core.insert_str("      x\n  y");
+        core.set_cursor_line_col(0, 0, false);
+        core.set_cursor_line_col(1, 3, true);
+        core.indent_lines(true);
+        assert_eq!(core.text(), "  x\ny");
+        assert_eq!(core.cursor(), Cursor { line: 1, col: 1 });
+    }
+
+    #[test]
+    fn select_word_at_selects_ascii_word() {
+        let mut core = Core::new();
+        core.insert_str("foo bar_baz qux");
+        assert!(core.select_word_at(0, 6));
+        assert_eq!(core.selection_range(), Some((4, 11)));
+    }
+
+    #[test]
+    fn select_word_at_splits_japanese_scripts() {
+        let mut core = Core::new();
+        core.insert_str("日本語のテキスト");
+        core.select_word_at(0, 1);
+        assert_eq!(core.selection_range(), Some((0, 3)));
+        core.select_word_at(0, 5);
+        assert_eq!(core.selection_range(), Some((4, 8)));
+    }
+
+    #[test]
+    fn select_line_includes_line_break() {
+        let mut core = Core::new();
+        core.insert_str("ab\ncd");
+        core.select_line(0);
+        assert_eq!(core.selection_range(), Some((0, 3)));
+        core.select_line(1);
+        assert_eq!(core.selection_range(), Some((3, 5)));
+    }
+
+    #[test]
+    fn selected_text_returns_selection() {
+        let mut core = Core::new();
+        core.insert_str("hello world");
+        assert_eq!(core.selected_text(), None);
+        core.set_cursor_line_col(0, 6, false);
+        core.set_cursor_line_col(0, 11, true);
+        assert_eq!(core.selected_text().as_deref(), Some("world"));
+    }
+
+    #[test]
+    fn cut_removes_selection_and_undoes() {
+        let mut core = Core::new();
+        core.insert_str("hello world");
+        core.set_cursor_line_col(0, 5, false);
+        core.set_cursor_line_col(0, 11, true);
+        assert_eq!(core.cut().as_deref(), Some(" world"));
+        assert_eq!(core.text(), "hello");
+        assert_eq!(core.cut(), None);
+        assert!(core.undo());
+        assert_eq!(core.text(), "hello world");
+    }
+
+    #[test]
+    fn typing_coalesces_until_a_new_word_starts() {
+        let mut core = Core::new();
+        for ch in ["h", "i", " ", "y", "o"] {
+            core.insert_str(ch);
+        }
+        assert!(core.undo());
+        assert_eq!(core.text(), "hi ");
+        assert!(core.undo());
+        assert_eq!(core.text(), "");
+        assert!(!core.undo());
+        assert!(core.redo());
+        assert_eq!(core.text(), "hi ");
+    }
+
+    #[test]
+    fn pause_or_motion_breaks_coalescing() {
+        let mut core = Core::new();
+        core.insert_str("a");
+        core.undo_tree.nodes[1].group.updated_at -= Core::COALESCE_INTERVAL * 2;
+        core.insert_str("b");
+        core.move_left(false);
+        core.move_right(false);
+        core.insert_str("c");
+        assert_eq!(core.undo_tree.nodes.len(), 4);
+    }
+
+    #[test]
+    fn backspace_and_delete_coalesce_separately() {
+        let mut core = Core::new();
+        core.insert_str("one two");
+        core.backspace();
+        core.backspace();
+        core.move_document_start(false);
+        core.delete_forward();
+        core.delete_forward();
+        assert_eq!(core.text(), "e t");
+        assert!(core.undo());
+        assert_eq!(core.text(), "one t");
+        assert_eq!(core.cursor(), Cursor { line: 0, col: 0 });
+        assert!(core.undo());
+        assert_eq!(core.text(), "one two");
+        assert_eq!(core.cursor(), Cursor { line: 0, col: 7 });
+    }
+
+    #[test]
+    fn explicit_group_undoes_atomically_and_restores_selection() {
+        let mut core = Core::new();
+        core.insert_str("abc\ndef");
+        core.set_cursor_line_col(0, 1, false);
+        core.set_cursor_line_col(1, 2, true);
+        core.begin_group();
+        core.insert_str("X");
+        core.begin_group();
+        core.insert_str("\n");
+        core.end_group();
+        core.end_group();
+        assert_eq!(core.text(), "aX\nf");
+        assert!(core.undo());
+        assert_eq!(core.text(), "abc\ndef");
+        assert_eq!(core.selected_text().as_deref(), Some("bc\nde"));
+        assert!(core.redo());
+        assert_eq!(core.text(), "aX\nf");
+        assert_eq!(core.selected_text(), None);
+    }
+
+    #[test]
+    fn undo_inside_a_group_starts_a_new_step() {
+        let mut core = Core::new();
+        core.insert_str("abc");
+        core.begin_group();
+        core.insert_str("\nX");
+        assert!(core.undo());
+        core.insert_str("Y");
+        core.end_group();
+        assert_eq!(core.text(), "abcY");
+        assert!(core.undo());
+        assert_eq!(core.text(), "abc");
+        assert!(core.undo());
+        assert_eq!(core.text(), "");
+    }
+
+    #[test]
+    fn loading_resets_an_unfinished_group() {
+        let mut core = Core::new();
+        core.begin_group();
+        core.load_from_bytes(b"abc");
+        core.move_document_end(false);
+        core.insert_str("d");
+        core.insert_str("e");
+        core.begin_group();
+        core.insert_str("f");
+        core.end_group();
+        assert!(core.undo());
+        assert_eq!(core.text(), "abcde");
+    }
+
+    #[test]
+    fn undo_back_to_saved_state_clears_dirty() {
+        let mut core = Core::new();
+        core.load_from_bytes(b"abc");
+        assert!(!core.is_dirty());
+        core.move_document_end(false);
+        core.insert_str("d");
+        assert!(core.is_dirty());
+        assert!(core.undo());
+        assert!(!core.is_dirty());
+        assert!(core.redo());
+        assert!(core.is_dirty());
+    }
+
+    #[test]
+    fn saving_seals_the_typing_group() {
+        let mut core = Core::new();
+        core.insert_str("a");
+        let point = core.save_point();
+        core.mark_saved_at(PathBuf::from("a.txt"), TextEncoding::Utf8, point);
+        assert!(!core.is_dirty());
+        core.insert_str("b");
+        assert!(core.is_dirty());
+        assert!(core.undo());
+        assert_eq!(core.text(), "a");
+        assert!(!core.is_dirty());
+        assert!(core.undo());
+        assert!(core.is_dirty());
+        core.insert_str("c");
+        assert!(core.is_dirty());
+    }
+
+    #[test]
+    fn editing_after_undo_keeps_the_old_branch() {
+        let mut core = Core::new();
+        core.insert_str("one");
+        core.insert_str("\n");
+        core.insert_str("two");
+        assert!(core.undo());
+        assert!(core.undo());
+        core.insert_str("!");
+        assert_eq!(core.text(), "one!");
+        assert!(!core.redo());
+
+        let branches = core.undo_branches();
+        assert_eq!(branches.len(), 2);
+        assert_eq!((branches[0].id, branches[0].changes, branches[0].current), (3, 3, false));
+        assert_eq!((branches[1].id, branches[1].changes, branches[1].current), (4, 2, true));
+
+        assert!(core.goto_undo_state(3));
+        assert_eq!(core.text(), "one\ntwo");
+        assert!(core.undo());
+        assert!(core.undo());
+        assert!(core.redo());
+        assert_eq!(core.text(), "one\n");
+    }
+
+    #[test]
+    fn undo_earlier_and_later_walk_states_chronologically() {
+        let mut core = Core::new();
+        core.insert_str("a");
+        core.insert_str("\n");
+        assert!(core.undo());
+        core.insert_str("b");
+        assert_eq!(core.text(), "ab");
+        assert!(core.undo_earlier());
+        assert_eq!(core.text(), "a\n");
+        assert!(core.undo_earlier());
+        assert_eq!(core.text(), "a");
+        assert!(core.undo_earlier());
+        assert_eq!(core.text(), "");
+        assert!(!core.undo_earlier());
+        assert!(core.undo_later());
+        assert!(core.undo_later());
+        assert!(core.undo_later());
+        assert_eq!(core.text(), "ab");
+        assert!(!core.undo_later());
+    }
+
+    #[test]
+    fn edits_during_a_save_stay_dirty() {
+        let mut core = Core::new();
+        core.insert_str("a");
+        let point = core.save_point();
+        // Typed while the save runs; must not merge into the saved step.
+        core.insert_str("b");
+        core.mark_saved_at(PathBuf::from("a.txt"), TextEncoding::Utf8, point);
+        assert!(core.is_dirty());
+        assert!(core.undo());
+        assert_eq!(core.text(), "a");
+        assert!(!core.is_dirty());
+
+        // The sidecar is anchored at what is on disk, not at the later edit.
+        let history = core.undo_history_bytes().unwrap();
+        let mut reopened = Core::new();
+        reopened.load_from_bytes(b"a");
+        assert!(reopened.restore_undo_history(&history));
+    }
+
+    #[test]
+    fn undo_history_round_trips_when_content_matches() {
+        let mut core = Core::new();
+        core.load_from_bytes(b"base");
+        core.move_document_end(false);
+        core.insert_str(" one");
+        let point = core.save_point();
+        core.mark_saved_at(PathBuf::from("a.txt"), TextEncoding::Utf8, point);
+        core.insert_str("\ntwo");
+        let history = core.undo_history_bytes().unwrap();
+
+        let mut reopened = Core::new();
+        reopened.load_from_bytes(b"base one");
+        assert!(reopened.restore_undo_history(&history));
+        assert!(!reopened.is_dirty());
+        assert!(reopened.redo());
+        assert_eq!(reopened.text(), "base one\ntwo");
+        assert!(reopened.undo());
+        assert!(reopened.undo());
+        assert_eq!(reopened.text(), "base");
+        assert!(reopened.is_dirty());
+    }
+
+    #[test]
+    fn undo_history_with_edits_that_do_not_fit_is_rejected() {
+        let mut core = Core::new();
+        core.load_from_bytes(b"base");
+        core.move_document_end(false);
+        core.insert_str(" one");
+        core.set_cursor_line_col(0, 0, true);
+        core.insert_str("two");
+        let point = core.save_point();
+        core.mark_saved_at(PathBuf::from("a.txt"), TextEncoding::Utf8, point);
+        let history = core.undo_history_bytes().unwrap();
+        let (tree, saved, hash) = UndoTree::from_bytes(&history).unwrap();
+
+        let tampered: [fn(&mut Edit); 4] = [
+            |edit| edit.cursor_before = 100,
+            |edit| edit.anchor_after = Some(100),
+            |edit| match &mut edit.kind {
+                EditKind::Insert { idx, .. } | EditKind::Replace { idx, .. } => *idx = 100,
+                EditKind::Delete { .. } => unreachable!(),
+            },
+            |edit| match &mut edit.kind {
+                EditKind::Insert { text, .. } => *text = "?".repeat(text.len()),
+                EditKind::Replace { deleted, .. } => *deleted = "base two".to_string(),
+                EditKind::Delete { .. } => unreachable!(),
+            },
+        ];
+        for (case, tamper) in tampered.iter().enumerate() {
+            for node in 1..tree.nodes.len() {
+                let mut tree = tree.clone();
+                tamper(&mut tree.nodes[node].group.edits[0]);
+                let mut reopened = Core::new();
+                reopened.load_from_bytes(b"two");
+                let forged = tree.to_bytes(saved, hash);
+                assert!(!reopened.restore_undo_history(&forged), "case {case}, node {node}");
+            }
+        }
+
+        let mut reopened = Core::new();
+        reopened.load_from_bytes(b"two");
+        assert!(reopened.restore_undo_history(&history));
+        assert!(reopened.undo());
+        assert_eq!(reopened.text(), "base one");
+    }
+
+    #[test]
+    fn undo_history_is_ignored_for_changed_or_corrupt_content() {
+        let mut core = Core::new();
+        core.load_from_bytes(b"base");
+        core.insert_str("x");
+        let point = core.save_point();
+        core.mark_saved_at(PathBuf::from("a.txt"), TextEncoding::Utf8, point);
+        let history = core.undo_history_bytes().unwrap();
+
+        let mut reopened = Core::new();
+        reopened.load_from_bytes(b"changed on disk");
+        assert!(!reopened.restore_undo_history(&history));
+        reopened.load_from_bytes(b"xbase");
+        assert!(!reopened.restore_undo_history(&history[..history.len() - 1]));
+        assert!(!reopened.undo());
+        assert!(reopened.restore_undo_history(&history));
+        assert!(reopened.undo());
+        assert_eq!(reopened.text(), "base");
+    }
+
+    #[test]
+    fn undo_stats_track_history_size() {
+        let mut core = Core::new();
+        assert_eq!(core.undo_stats().entries, 0);
+        core.insert_str("abc");
+        core.insert_str("\n");
+        let stats = core.undo_stats();
+        assert_eq!(stats.entries, 2);
+        assert!(stats.bytes >= 4);
+        assert!(core.undo());
+        assert_eq!(core.undo_stats(), stats);
+    }
+
+    #[test]
+    fn undo_budget_drops_oldest_steps_but_keeps_the_latest() {
+        let mut core = Core::new();
+        let big = "x".repeat(1000);
+        for _ in 0..5 {
+            core.insert_str(&big);
+            core.insert_str("\n");
+        }
+        assert_eq!(core.undo_stats().entries, 10);
+        core.set_undo_budget(2500);
+        assert!(core.undo_stats().bytes <= 2500);
+        let mut steps = 0;
+        while core.undo() {
+            steps += 1;
+        }
+        assert!((1..10).contains(&steps));
+        assert!(core.text().starts_with(&big));
+        assert!(core.is_dirty());
+
+        core.set_undo_budget(0);
+        core.insert_str(&big);
+        assert_eq!(core.undo_stats().entries, 1);
+        assert!(core.undo());
+        assert!(!core.undo());
+    }
+
+    #[test]
+    fn pruning_leaves_room_for_the_next_edits() {
+        let mut core = Core::new();
+        let big = "x".repeat(1000);
+        for _ in 0..5 {
+            core.insert_str(&big);
+            core.insert_str("\n");
+        }
+        let budget = core.undo_stats().bytes - 1;
+        core.set_undo_budget(budget);
+        let stats = core.undo_stats();
+        assert!(stats.bytes <= budget * 3 / 4);
+        core.insert_str("\n");
+        assert_eq!(core.undo_stats().entries, stats.entries + 1);
+    }
+
+    #[test]
+    fn pruned_saved_state_is_not_persisted() {
+        let mut core = Core::new();
+        core.load_from_bytes(b"abc");
+        assert!(core.undo_history_bytes().is_some());
+        let big = "x".repeat(1000);
+        for _ in 0..5 {
+            core.insert_str(&big);
+            core.insert_str("\n");
+        }
+        core.set_undo_budget(2500);
+        assert!(core.is_dirty());
+        assert_eq!(core.undo_history_bytes(), None);
+    }
+
+    #[test]
+    fn detects_bom_and_bomless_unicode() {
+        let detected = detect_encoding(b"\xEF\xBB\xBFabc");
+        assert_eq!((detected.encoding, detected.bom_len), (TextEncoding::Utf8Bom, 3));
+        assert_eq!(detect_encoding(b"plain").confidence, 1.0);
+
+        let detected = detect_encoding("日本語のテキスト".as_bytes());
+        assert_eq!(detected.encoding, TextEncoding::Utf8);
+        assert!(detected.confidence > 0.9);
+
+        let utf16le: Vec<u8> = "hello, world".encode_utf16().flat_map(u16::to_le_bytes).collect();
+        assert_eq!(detect_encoding(&utf16le).encoding, TextEncoding::Utf16Le);
+        let utf16be: Vec<u8> = "hello, world".encode_utf16().flat_map(u16::to_be_bytes).collect();
+        assert_eq!(detect_encoding(&utf16be).encoding, TextEncoding::Utf16Be);
+    }
+
+    #[test]
+    fn detects_shift_jis_and_euc_jp() {
+        let text = "これは日本語の文章です。カタカナも含みます。";
+        let (sjis, _, _) = SHIFT_JIS.encode(text);
+        let detected = detect_encoding(&sjis);
+        assert_eq!(detected.encoding, TextEncoding::ShiftJis);
+        assert!(detected.confidence > 0.9);
+
+        let (euc, _, _) = EUC_JP.encode(text);
+        assert_eq!(detect_encoding(&euc).encoding, TextEncoding::EucJp);
+
+        let mut core = Core::new();
+        let detected = core.load_from_bytes(&sjis);
+        assert_eq!(detected.encoding, TextEncoding::ShiftJis);
+        assert_eq!(core.text(), text);
+        assert_eq!(core.encoding(), TextEncoding::ShiftJis);
+    }
+
+    #[test]
+    fn detects_half_width_katakana() {
+        for text in ["ｱｲｳｴｵ", "ﾃｽﾄﾃﾞｰﾀ", "商品ｺｰﾄﾞ: A-100"] {
+            let (sjis, _, _) = SHIFT_JIS.encode(text);
+            let detected = detect_encoding(&sjis);
+            assert_eq!(detected.encoding, TextEncoding::ShiftJis, "{text}");
+            assert!(detected.confidence > 0.9, "{text}: {}", detected.confidence);
+            assert!(detected.confidence < 1.0);
+        }
+
+        // These bytes are also two valid EUC-JP kanji.
+        let (sjis, _, _) = SHIFT_JIS.encode("ｱｲｳｴ");
+        let detected = detect_encoding(&sjis);
+        assert_eq!(detected.encoding, TextEncoding::ShiftJis);
+        assert!(detected.confidence < 0.9);
+
+        let (latin, _, _) = WINDOWS_1252.encode("Straße, ÄRGER");
+        assert_eq!(detect_encoding(&latin).encoding, TextEncoding::Windows1252);
+    }
+
+    #[test]
+    fn short_samples_are_never_certain() {
+        let (sjis, _, _) = SHIFT_JIS.encode("ア");
+        let detected = detect_encoding(&sjis);
+        assert_eq!(detected.encoding, TextEncoding::ShiftJis);
+        assert!(detected.confidence < 0.9);
+
+        let (euc, _, _) = EUC_JP.encode("あ");
+        let detected = detect_encoding(&euc);
+        assert!(detected.confidence < 0.9);
+
+        let long = "日本語".repeat(20);
+        assert!(detect_encoding(long.as_bytes()).confidence < 1.0);
+        let (sjis, _, _) = SHIFT_JIS.encode(&long);
+        assert!(detect_encoding(&sjis).confidence < 1.0);
+    }
+
+    #[test]
+    fn every_encoding_round_trips_through_from_encoding() {
+        for encoding in TextEncoding::ALL.into_iter().filter(|e| *e != TextEncoding::Utf8Bom) {
+            assert_eq!(TextEncoding::from_encoding(encoding.encoding()), Some(encoding));
+            assert_eq!(encoding.label(), encoding.encoding().name());
+        }
+        assert_eq!(TextEncoding::Windows1252.label(), "windows-1252");
+        assert_eq!(TextEncoding::from_encoding(encoding_rs::REPLACEMENT), None);
+    }
+
+    #[test]
+    fn utf8_bom_round_trips_and_can_be_dropped() {
+        let bytes = b"\xEF\xBB\xBFa,b\n";
+        let mut core = Core::new();
+        core.load_from_bytes(bytes);
+        assert_eq!(core.text(), "a,b\n");
+        assert_eq!(core.encoding(), TextEncoding::Utf8Bom);
+        assert_eq!(core.encoding().label(), "UTF-8 with BOM");
+        assert_eq!(Core::encode_text(&core.text(), core.encoding(), core.line_ending()), bytes);
+
+        core.set_encoding(TextEncoding::Utf8);
+        assert_eq!(Core::encode_text(&core.text(), core.encoding(), core.line_ending()), b"a,b\n");
+
+        core.load_from_bytes_as(bytes, TextEncoding::Utf8);
+        assert_eq!((core.text().as_str(), core.encoding()), ("a,b\n", TextEncoding::Utf8Bom));
+        core.load_from_bytes(b"a,b\n");
+        assert_eq!(core.encoding(), TextEncoding::Utf8);
+    }
+
+    #[test]
+    fn detects_iso_2022_jp_and_windows_1252() {
+        let (jis, _, _) = ISO_2022_JP.encode("メールの本文");
+        let detected = detect_encoding(&jis);
+        assert_eq!(detected.encoding, TextEncoding::Iso2022Jp);
+        assert!(detected.confidence > 0.9);
+
+        let (latin, _, _) = WINDOWS_1252.encode("naïve café, 10€");
+        let detected = detect_encoding(&latin);
+        assert_eq!(detected.encoding, TextEncoding::Windows1252);
+        assert!(detected.confidence < 0.5);
+
+        let mut core = Core::new();
+        core.load_from_bytes(&latin);
+        assert_eq!(core.text(), "naïve café, 10€");
+        assert!(core.check_encodable(TextEncoding::Windows1252).is_ok());
+        assert!(core.check_encodable(TextEncoding::ShiftJis).is_err());
+    }
+
+    #[test]
+    fn load_as_redecodes_with_the_chosen_encoding() {
+        let (euc, _, _) = EUC_JP.encode("かな");
+        let mut core = Core::new();
+        core.load_from_bytes_as(&euc, TextEncoding::ShiftJis);
+        assert_ne!(core.text(), "かな");
+        core.load_from_bytes_as(&euc, TextEncoding::EucJp);
+        assert_eq!(core.text(), "かな");
+        assert_eq!(core.encoding(), TextEncoding::EucJp);
+        assert!(!core.is_dirty());
+
+        core.load_from_bytes_as(b"\xFF\xFEa\0", TextEncoding::Utf16Le);
+        assert_eq!(core.text(), "a");
+    }
+
+    #[test]
+    fn decode_errors_report_byte_offsets() {
+        let mut core = Core::new();
+        core.load_from_bytes_as(b"ok\xFFok\xFE", TextEncoding::Utf8);
+        assert_eq!(core.text(), "ok\u{FFFD}ok\u{FFFD}");
+        let err = core.decode_error().expect("malformed input");
+        assert!(matches!(
+            err,
+            CoreError::System(SystemError { kind: SystemErrorKind::Encoding, .. })
+        ));
+        assert_eq!(err.positions(), &[2, 5]);
+        assert_eq!(core.encoding_issue_cols(0), vec![2, 5]);
+
+        core.load_from_bytes(b"clean");
+        assert!(core.decode_error().is_none());
+    }
+
+    #[test]
+    fn unencodable_chars_are_reported_before_a_lossy_save() {
+        let mut core = Core::new();
+        core.insert_str("日本\n語😀x😀");
+        assert!(core.check_encodable(TextEncoding::Utf8).is_ok());
+        let err = core.check_encodable(TextEncoding::ShiftJis).unwrap_err();
+        assert!(matches!(
+            err,
+            CoreError::Domain(DomainError { kind: DomainErrorKind::Unencodable, .. })
+        ));
+        assert_eq!(err.positions(), &[4, 6]);
+        core.set_encoding(TextEncoding::ShiftJis);
+        assert_eq!(core.encoding_issue_cols(1), vec![1, 3]);
+        assert!(core.encoding_issue_cols(0).is_empty());
+    }
+
+    #[test]
+    fn utf16_is_encoded_as_utf16() {
+        assert_eq!(
+            Core::encode_text("a語", TextEncoding::Utf16Le, LineEnding::Lf),
+            vec![0xFF, 0xFE, 0x61, 0x00, 0x9E, 0x8A]
+        );
+        assert_eq!(
+            Core::encode_text("a", TextEncoding::Utf16Be, LineEnding::Lf),
+            vec![0xFE, 0xFF, 0x00, 0x61]
+        );
+    }
+
+    #[test]
+    fn paste_replaces_selection() {
+        let mut core = Core::new();
+        core.insert_str("hello world");
+        core.set_cursor_line_col(0, 0, false);
+        core.set_cursor_line_col(0, 5, true);
+        core.insert_str("goodbye");
+        assert_eq!(core.text(), "goodbye world");
+        assert!(core.undo());
+        assert_eq!(core.text(), "hello world");
+    }
+
+    #[test]
+    fn delete_forward_removes_next_char_or_selection() {
+        let mut core = Core::new();
+        core.insert_str("abc");
+        core.set_cursor_line_col(0, 1, false);
+        core.delete_forward();
+        assert_eq!(core.text(), "ac");
+        core.set_cursor_line_col(0, 2, false);
+        core.delete_forward();
+        assert_eq!(core.text(), "ac");
+        core.set_cursor_line_col(0, 0, false);
+        core.set_cursor_line_col(0, 2, true);
+        core.delete_forward();
+        assert_eq!(core.text(), "");
+        assert!(core.undo());
+        assert_eq!(core.text(), "ac");
+    }
+
+    #[test]
+    fn delete_word_backward_skips_whitespace_then_word() {
+        let mut core = Core::new();
+        core.insert_str("let foo_bar  ");
+        core.delete_word_backward();
+        assert_eq!(core.text(), "let ");
+        core.delete_word_backward();
+        assert_eq!(core.text(), "");
+        assert!(core.undo());
+        assert_eq!(core.text(), "let ");
+        assert_eq!(core.cursor(), Cursor { line: 0, col: 4 });
+    }
+
+    #[test]
+    fn delete_word_backward_stops_at_script_change_and_line_break() {
+        let mut core = Core::new();
+        core.insert_str("a\n日本語テキスト");
+        core.delete_word_backward();
+        assert_eq!(core.text(), "a\n日本語");
+        core.delete_word_backward();
+        core.delete_word_backward();
+        assert_eq!(core.text(), "a");
+    }
+
+    #[test]
+    fn delete_to_line_start_and_kill_to_line_end() {
+        let mut core = Core::new();
+        core.insert_str("ab\ncdef");
+        core.set_cursor_line_col(1, 2, false);
+        core.delete_to_line_start();
+        assert_eq!(core.text(), "ab\nef");
+        core.delete_to_line_start();
+        assert_eq!(core.text(), "abef");
+        core.set_cursor_line_col(0, 2, false);
+        core.kill_to_line_end();
+        assert_eq!(core.text(), "ab");
+        core.kill_to_line_end();
+        assert_eq!(core.text(), "ab");
+        assert!(core.undo());
+        assert_eq!(core.text(), "abef");
+    }
+
+    #[test]
+    fn word_motion_stops_at_word_and_script_boundaries() {
+        let mut core = Core::new();
+        core.insert_str("foo  bar.baz 東京タワーへ");
+        core.move_document_start(false);
+        core.move_word_right(false);
+        assert_eq!(core.cursor().col, 3);
+        core.move_word_right(false);
+        assert_eq!(core.cursor().col, 8);
+        core.move_word_right(false);
+        core.move_word_right(false);
+        assert_eq!(core.cursor().col, 12);
+        core.move_word_right(false);
+        assert_eq!(core.cursor().col, 15);
+        core.move_word_right(false);
+        assert_eq!(core.cursor().col, 18);
+        core.move_word_left(true);
+        assert_eq!(core.cursor().col, 15);
+        assert_eq!(core.selection_range(), Some((15, 18)));
+    }
+
+    #[test]
+    fn word_motion_keeps_combining_marks_with_base() {
+        let mut core = Core::new();
+        // "ガギ" written with combining dakuten, followed by kanji.
+        core.insert_str("カ\u{3099}キ\u{3099}漢字");
+        core.move_document_start(false);
+        core.move_word_right(false);
+        assert_eq!(core.cursor().col, 4);
+    }
+
+    #[test]
+    fn line_and_document_boundary_motion() {
+        let mut core = Core::new();
+        core.insert_str("abc\ndef\nghi");
+        core.set_cursor_line_col(1, 1, false);
+        core.move_line_end(false);
+        assert_eq!(core.cursor(), Cursor { line: 1, col: 3 });
+        core.move_line_start(true);
+        assert_eq!(core.selection_range(), Some((4, 7)));
+        core.move_document_start(false);
+        assert_eq!(core.cursor(), Cursor { line: 0, col: 0 });
+        core.move_document_end(true);
+        assert_eq!(core.cursor(), Cursor { line: 2, col: 3 });
+        assert_eq!(core.selection_range(), Some((0, 11)));
+    }
+
+    #[test]
+    fn page_motion_clamps_to_document() {
+        let mut core = Core::new();
+        core.insert_str("0\n1\n2\n3\n4\n5");
+        core.move_document_start(false);
+        core.move_page_down(4, false);
+        assert_eq!(core.cursor().line, 4);
+        core.move_page_down(4, false);
+        assert_eq!(core.cursor().line, 5);
+        core.move_page_up(4, true);
+        assert_eq!(core.cursor().line, 1);
+        assert!(core.has_selection());
+    }
+
+    #[test]
+    fn vertical_motion_keeps_goal_column_across_short_lines() {
+        let mut core = Core::new();
+        core.insert_str("abcdef\nab\nabcdef");
+        core.set_cursor_line_col(0, 5, false);
+        core.move_down(false);
+        assert_eq!(core.cursor(), Cursor { line: 1, col: 2 });
+        core.move_down(false);
+        assert_eq!(core.cursor(), Cursor { line: 2, col: 5 });
+        core.move_up(false);
+        core.move_up(false);
+        assert_eq!(core.cursor(), Cursor { line: 0, col: 5 });
+    }
+
+    #[test]
+    fn goal_column_resets_on_horizontal_motion_and_edits() {
+        let mut core = Core::new();
+        core.insert_str("abcdef\nab\nabcdef");
+        core.set_cursor_line_col(0, 5, false);
+        core.move_down(false);
+        core.move_left(false);
+        core.move_down(false);
+        assert_eq!(core.cursor(), Cursor { line: 2, col: 1 });
+        core.set_cursor_line_col(0, 5, false);
+        core.move_down(false);
+        core.insert_str("x");
+        core.move_down(false);
+        assert_eq!(core.cursor(), Cursor { line: 2, col: 3 });
+    }
+
+    #[test]
+    fn goal_column_uses_display_columns_for_tabs() {
+        let mut core = Core::new();
+        core.insert_str("\tx\nabcdef");
+        core.set_cursor_line_col(0, 1, false);
+        core.move_down(false);
+        assert_eq!(core.cursor(), Cursor { line: 1, col: 4 });
+        core.set_cursor_line_col(1, 1, false);
+        core.move_up(false);
+        assert_eq!(core.cursor(), Cursor { line: 0, col: 0 });
+    }
+
+    const FAMILY: &str = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+    const FLAG_JP: &str = "\u{1F1EF}\u{1F1F5}";
+    const GA_COMBINING: &str = "\u{304B}\u{3099}";
+
+    #[test]
+    fn cursor_motion_steps_over_grapheme_clusters() {
+        let mut core = Core::new();
+        core.insert_str(&format!("a{FAMILY}{FLAG_JP}{GA_COMBINING}b"));
+        core.move_document_start(false);
+        core.move_right(false);
+        core.move_right(false);
+        assert_eq!(core.cursor().col, 6);
+        core.move_right(false);
+        assert_eq!(core.cursor().col, 8);
+        core.move_right(false);
+        assert_eq!(core.cursor().col, 10);
+        core.move_left(true);
+        core.move_left(true);
+        assert_eq!(core.selection_range(), Some((6, 10)));
+    }
+
+    #[test]
+    fn backspace_and_delete_remove_whole_clusters() {
+        let mut core = Core::new();
+        core.insert_str(&format!("{FLAG_JP}{FAMILY}{GA_COMBINING}"));
+        core.backspace();
+        assert_eq!(core.text(), format!("{FLAG_JP}{FAMILY}"));
+        core.backspace();
+        assert_eq!(core.text(), FLAG_JP);
+        core.move_document_start(false);
+        core.delete_forward();
+        assert_eq!(core.text(), "");
+        assert!(core.undo());
+        assert_eq!(core.text(), FLAG_JP);
+    }
+
+    #[test]
+    fn set_cursor_line_col_snaps_into_cluster_start() {
+        let mut core = Core::new();
+        core.insert_str(&format!("x{FAMILY}"));
+        core.set_cursor_line_col(0, 3, false);
+        assert_eq!(core.cursor().col, 1);
+        core.set_cursor_line_col(0, 6, false);
+        assert_eq!(core.cursor().col, 6);
+    }
+
+    #[test]
+    fn crlf_is_a_single_cluster() {
+        let mut core = Core::new();
+        core.insert_str("a\r\nb");
+        core.set_cursor_line_col(1, 0, false);
+        core.backspace();
+        assert_eq!(core.text(), "ab");
+    }
+
+    #[test]
+    fn line_diff_keeps_common_lines() {
+        use DiffLine::{Added, Removed, Same};
+        assert_eq!(
+            line_diff("a\nb\nc\nd\n", "a\nc\nx\nd\n"),
+            vec![Same("a"), Removed("b"), Same("c"), Added("x"), Same("d")]
+        );
+        assert_eq!(line_diff("same", "same"), vec![Same("same")]);
+        assert_eq!(line_diff("", "new"), vec![Added("new")]);
+    }
+
+    #[test]
+    fn append_bytes_decodes_split_characters_and_stays_clean() {
+        let (sjis, _, _) = SHIFT_JIS.encode("ログ1\r\nログ2\r\n");
+        let mut core = Core::new();
+        core.load_from_bytes_as(&sjis[..7], TextEncoding::ShiftJis);
+        assert_eq!(core.text(), "ログ1\n");
+        // Split inside a two-byte character and between CR and LF.
+        core.append_bytes(&sjis[7..8]);
+        core.append_bytes(&sjis[8..13]);
+        assert_eq!(core.text(), "ログ1\nログ2");
+        core.append_bytes(&sjis[13..]);
+        assert_eq!(core.text(), "ログ1\nログ2\n");
+        assert!(!core.is_dirty());
+
+        core.insert_str("x");
+        core.append_bytes(b"tail");
+        assert!(core.is_dirty());
+        assert!(core.text().ends_with("tail"));
+    }
+
+    #[test]
+    fn detects_dominant_and_mixed_line_endings() {
+        assert_eq!(detect_line_ending("no breaks"), (LineEnding::Lf, false));
+        assert_eq!(detect_line_ending("a\r\nb\r\n"), (LineEnding::CrLf, false));
+        assert_eq!(detect_line_ending("a\rb\r"), (LineEnding::Cr, false));
+        assert_eq!(detect_line_ending("a\r\nb\r\nc\n"), (LineEnding::CrLf, true));
+        assert_eq!(detect_line_ending("a\r\nb\n"), (LineEnding::Lf, true));
+    }
+
+    #[test]
+    fn crlf_files_are_normalized_and_saved_as_crlf() {
+        let mut core = Core::new();
+        core.load_from_bytes(b"one\r\ntwo\r\nthree\nfour");
+        assert_eq!(core.text(), "one\ntwo\nthree\nfour");
+        assert_eq!(core.rope.len_lines(), 4);
+        assert_eq!(line_len_chars(&core.rope, 0), 3);
+        assert_eq!((core.line_ending(), core.has_mixed_line_endings()), (LineEnding::CrLf, true));
+
+        core.set_cursor_line_col(0, 3, false);
+        core.insert_str("\n");
+        core.insert_str("pasted\r\n");
+        let saved = Core::encode_text(&core.text(), core.encoding(), core.line_ending());
+        assert_eq!(saved, b"one\r\npasted\r\n\r\ntwo\r\nthree\r\nfour");
+
+        core.set_line_ending(LineEnding::Lf);
+        assert!(!core.has_mixed_line_endings());
+        let saved = Core::encode_text(&core.text(), core.encoding(), core.line_ending());
+        assert_eq!(saved, b"one\npasted\n\ntwo\nthree\nfour");
+        let saved = Core::encode_text("a\nb", core.encoding(), LineEnding::Cr);
+        assert_eq!(saved, b"a\rb");
+    }
+
+    #[test]
+    fn converting_line_endings_marks_the_document_dirty() {
+        let mut core = Core::new();
+        core.load_from_bytes(b"one\r\ntwo\r\n");
+        core.set_line_ending(LineEnding::CrLf);
+        assert!(!core.is_dirty());
+        core.set_line_ending(LineEnding::Lf);
+        assert!(core.is_dirty());
+        core.insert_str("x");
+        assert!(core.undo());
+        assert!(core.is_dirty());
+
+        let point = core.save_point();
+        core.mark_saved_at(PathBuf::from("a.txt"), TextEncoding::Utf8, point);
+        assert!(!core.is_dirty());
+    }
+
+    #[test]
+    fn display_col_uses_east_asian_width() {
+        let mut core = Core::new();
+        core.insert_str(&format!("aあＡ{GA_COMBINING}{FAMILY}b"));
+        assert_eq!(core.display_col(0, 1), 1);
+        assert_eq!(core.display_col(0, 2), 3);
+        assert_eq!(core.display_col(0, 3), 5);
+        assert_eq!(core.display_col(0, 5), 7);
+        assert_eq!(core.display_col(0, 10), 9);
+    }
+
+    #[test]
+    fn goal_column_crosses_full_width_text() {
+        let mut core = Core::new();
+        core.insert_str("abcd\nあいう");
+        core.set_cursor_line_col(0, 2, false);
+        core.move_down(false);
+        assert_eq!(core.cursor(), Cursor { line: 1, col: 1 });
+        core.move_up(false);
+        assert_eq!(core.cursor(), Cursor { line: 0, col: 2 });
     }
 }
@@ -1,5 +1,7 @@
 mod app;
+mod clipboard;
 mod core;
+mod storage;
 mod ui;
 
 fn main() {
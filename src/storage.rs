@@ -0,0 +1,449 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use crate::core::{ContentHasher, CoreError, content_hash};
+
+/// Symlink hops followed before giving up, matching the usual `ELOOP` limit.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Replaces the file at `path` with `bytes` so that a crash or a full disk
+/// leaves either the old or the new contents, never a truncated file.
+///
+/// The bytes go to a temporary file next to the target, which is synced and
+/// renamed over it. The target keeps its permissions, and if `path` is a
+/// symlink the file it points to is replaced rather than the link.
+pub fn save_atomic(path: &Path, bytes: &[u8]) -> Result<(), CoreError> {
+    let target = resolve_symlinks(path)?;
+    let permissions = match fs::metadata(&target) {
+        Ok(metadata) => Some(metadata.permissions()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => {
+            return Err(CoreError::from_io(
+                format!("read metadata of {}", target.display()),
+                err,
+            ));
+        }
+    };
+    let dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let (temp_path, file) = create_temp_file(&dir, &target)?;
+    let result = write_temp_file(file, &temp_path, bytes, permissions).and_then(|()| {
+        fs::rename(&temp_path, &target).map_err(|err| {
+            CoreError::from_io(
+                format!("replace {} with {}", target.display(), temp_path.display()),
+                err,
+            )
+        })
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+        return result;
+    }
+    // The rename is only durable once the directory entry is on disk. The
+    // new contents are already in place, so a failure here is not an error.
+    if let Err(err) = File::open(&dir).and_then(|dir| dir.sync_all()) {
+        eprintln!("[save] failed to sync {}: {err}", dir.display());
+    }
+    Ok(())
+}
+
+/// What a file on disk looked like when it was last read or written, to
+/// notice other programs changing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    pub modified: Option<SystemTime>,
+    pub len: u64,
+    /// `content_hash` of the bytes.
+    pub hash: u64,
+}
+
+impl FileStamp {
+    /// Stamp for a file with `metadata` whose contents are `bytes`.
+    pub fn new(metadata: &fs::Metadata, bytes: &[u8]) -> Self {
+        Self {
+            modified: metadata.modified().ok(),
+            len: bytes.len() as u64,
+            hash: content_hash(bytes),
+        }
+    }
+}
+
+/// Result of comparing a file on disk with its last known `FileStamp`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DiskChange {
+    Unchanged,
+    /// Another program changed the contents; holds the new stamp and bytes.
+    Modified { stamp: FileStamp, bytes: Vec<u8> },
+    /// The file was deleted or renamed away.
+    Missing,
+}
+
+/// Compares the file at `path` with `stamp`. The contents are only read when
+/// the size or modification time differ; if they turn out to be the same,
+/// `stamp` takes the new time so the next check is cheap again.
+pub fn check_disk(path: &Path, stamp: &mut FileStamp) -> Result<DiskChange, CoreError> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(DiskChange::Missing),
+        Err(err) => {
+            return Err(CoreError::from_io(format!("stat {}", path.display()), err));
+        }
+    };
+    if metadata.len() == stamp.len && metadata.modified().ok() == stamp.modified {
+        return Ok(DiskChange::Unchanged);
+    }
+    let bytes = fs::read(path)
+        .map_err(|err| CoreError::from_io(format!("read {}", path.display()), err))?;
+    let current = FileStamp::new(&metadata, &bytes);
+    if current.len == stamp.len && current.hash == stamp.hash {
+        stamp.modified = current.modified;
+        return Ok(DiskChange::Unchanged);
+    }
+    Ok(DiskChange::Modified {
+        stamp: current,
+        bytes,
+    })
+}
+
+/// For a file that only grows, like a log: reads the bytes added since
+/// `stamp` and moves `stamp` past them. As with `tail -f`, the earlier
+/// contents are assumed unchanged. `None` if the file is gone, shrank, or
+/// changed without growing, which needs a full `check_disk`.
+pub fn read_appended(path: &Path, stamp: &mut FileStamp) -> Result<Option<Vec<u8>>, CoreError> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => {
+            return Err(CoreError::from_io(format!("stat {}", path.display()), err));
+        }
+    };
+    let modified = metadata.modified().ok();
+    if metadata.len() <= stamp.len {
+        let unchanged = metadata.len() == stamp.len && modified == stamp.modified;
+        return Ok(unchanged.then(Vec::new));
+    }
+    let mut bytes = Vec::new();
+    File::open(path)
+        .and_then(|mut file| {
+            file.seek(SeekFrom::Start(stamp.len))?;
+            file.read_to_end(&mut bytes)
+        })
+        .map_err(|err| CoreError::from_io(format!("read the end of {}", path.display()), err))?;
+    let mut hasher = ContentHasher::resume(stamp.hash);
+    hasher.write(&bytes);
+    *stamp = FileStamp {
+        modified,
+        len: stamp.len + bytes.len() as u64,
+        hash: hasher.finish(),
+    };
+    Ok(Some(bytes))
+}
+
+/// Where `backup_previous` keeps copies of the file about to be replaced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackupLocation {
+    /// Next to the file, as `name~`, `name~2`, ...
+    Sibling,
+    /// In one directory for all files, as `name.<path hash>~`, ... so that
+    /// files with the same name don't share backups.
+    Directory(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupConfig {
+    pub location: BackupLocation,
+    /// Backups kept per file; the oldest is dropped once there are more.
+    pub generations: usize,
+    /// Refuse to save when the backup fails instead of only reporting it.
+    pub strict: bool,
+}
+
+/// Copies the current contents of `path` to its newest backup slot, shifting
+/// older backups down one generation first. Returns the backup written, or
+/// `None` if there was no file to back up.
+pub fn backup_previous(path: &Path, config: &BackupConfig) -> Result<Option<PathBuf>, CoreError> {
+    if config.generations == 0 {
+        return Ok(None);
+    }
+    let target = resolve_symlinks(path)?;
+    if !target.is_file() {
+        return Ok(None);
+    }
+    if let BackupLocation::Directory(dir) = &config.location {
+        fs::create_dir_all(dir).map_err(|err| {
+            CoreError::from_io(format!("create backup directory {}", dir.display()), err)
+        })?;
+    }
+    let slots: Vec<PathBuf> = (1..=config.generations)
+        .map(|generation| backup_path(&target, &config.location, generation))
+        .collect();
+    for pair in slots.windows(2).rev() {
+        let (newer, older) = (&pair[0], &pair[1]);
+        match fs::rename(newer, older) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                return Err(CoreError::from_io(
+                    format!("rotate backup {} to {}", newer.display(), older.display()),
+                    err,
+                ));
+            }
+            _ => {}
+        }
+    }
+    fs::copy(&target, &slots[0]).map_err(|err| {
+        CoreError::from_io(
+            format!("back up {} to {}", target.display(), slots[0].display()),
+            err,
+        )
+    })?;
+    Ok(Some(slots[0].clone()))
+}
+
+/// Path of backup `generation` (1 = newest) of `target`.
+fn backup_path(target: &Path, location: &BackupLocation, generation: usize) -> PathBuf {
+    let name = target
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let suffix = if generation == 1 {
+        "~".to_string()
+    } else {
+        format!("~{generation}")
+    };
+    match location {
+        BackupLocation::Sibling => target.with_file_name(format!("{name}{suffix}")),
+        BackupLocation::Directory(dir) => {
+            let hash = content_hash(target.to_string_lossy().as_bytes());
+            dir.join(format!("{name}.{hash:016x}{suffix}"))
+        }
+    }
+}
+
+/// Follows `path` through any chain of symlinks to the file it names. A
+/// dangling link resolves to the missing file, which the save then creates.
+fn resolve_symlinks(path: &Path) -> Result<PathBuf, CoreError> {
+    let mut current = path.to_path_buf();
+    for _ in 0..MAX_SYMLINK_HOPS {
+        match fs::symlink_metadata(&current) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                let link = fs::read_link(&current).map_err(|err| {
+                    CoreError::from_io(format!("resolve symlink {}", current.display()), err)
+                })?;
+                current = match current.parent() {
+                    Some(parent) if link.is_relative() => parent.join(link),
+                    _ => link,
+                };
+            }
+            _ => return Ok(current),
+        }
+    }
+    Err(CoreError::from_io(
+        format!("resolve symlink {}", path.display()),
+        std::io::Error::other("too many levels of symbolic links"),
+    ))
+}
+
+fn create_temp_file(dir: &Path, target: &Path) -> Result<(PathBuf, File), CoreError> {
+    let name = target
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    loop {
+        let counter = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let temp_path = dir.join(format!(".{name}.{}.{counter}.tmp", std::process::id()));
+        match OpenOptions::new().write(true).create_new(true).open(&temp_path) {
+            Ok(file) => return Ok((temp_path, file)),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(err) => {
+                return Err(CoreError::from_io(
+                    format!("create temporary file in {}", dir.display()),
+                    err,
+                ));
+            }
+        }
+    }
+}
+
+fn write_temp_file(
+    mut file: File,
+    temp_path: &Path,
+    bytes: &[u8],
+    permissions: Option<fs::Permissions>,
+) -> Result<(), CoreError> {
+    file.write_all(bytes)
+        .map_err(|err| CoreError::from_io(format!("write {}", temp_path.display()), err))?;
+    if let Some(permissions) = permissions {
+        file.set_permissions(permissions).map_err(|err| {
+            CoreError::from_io(format!("copy permissions to {}", temp_path.display()), err)
+        })?;
+    }
+    file.sync_all()
+        .map_err(|err| CoreError::from_io(format!("sync {}", temp_path.display()), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("notepad-storage-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn stamp_of(path: &Path) -> FileStamp {
+        FileStamp::new(&fs::metadata(path).unwrap(), &fs::read(path).unwrap())
+    }
+
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn save_atomic_creates_and_replaces_without_leftovers() {
+        let dir = test_dir("replace");
+        let path = dir.join("notes.txt");
+        save_atomic(&path, b"first").unwrap();
+        save_atomic(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(entries(&dir), vec!["notes.txt"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn save_atomic_reports_the_failing_stage() {
+        let dir = test_dir("stage");
+        let path = dir.join("missing").join("notes.txt");
+        let err = save_atomic(&path, b"text").unwrap_err();
+        assert!(err.describe().contains("create temporary file in"), "{}", err.describe());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn check_disk_ignores_touches_and_reports_rewrites() {
+        let dir = test_dir("stamp");
+        let path = dir.join("log.txt");
+        fs::write(&path, b"one").unwrap();
+        let mut stamp = stamp_of(&path);
+        assert_eq!(check_disk(&path, &mut stamp).unwrap(), DiskChange::Unchanged);
+
+        // Same bytes with a different time only refreshes the stamp.
+        let file = File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH).unwrap();
+        assert_eq!(check_disk(&path, &mut stamp).unwrap(), DiskChange::Unchanged);
+        assert_eq!(stamp.modified, Some(SystemTime::UNIX_EPOCH));
+
+        fs::write(&path, b"two!").unwrap();
+        match check_disk(&path, &mut stamp).unwrap() {
+            DiskChange::Modified { stamp: current, bytes } => {
+                assert_eq!(bytes, b"two!");
+                assert_eq!(current, stamp_of(&path));
+            }
+            other => panic!("expected a modification, got {other:?}"),
+        }
+        fs::remove_file(&path).unwrap();
+        assert_eq!(check_disk(&path, &mut stamp).unwrap(), DiskChange::Missing);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn read_appended_returns_only_new_bytes() {
+        let dir = test_dir("append");
+        let path = dir.join("app.log");
+        fs::write(&path, b"line 1\n").unwrap();
+        let mut stamp = stamp_of(&path);
+        assert_eq!(read_appended(&path, &mut stamp).unwrap(), Some(Vec::new()));
+
+        let mut file = File::options().append(true).open(&path).unwrap();
+        file.write_all(b"line 2\n").unwrap();
+        assert_eq!(read_appended(&path, &mut stamp).unwrap(), Some(b"line 2\n".to_vec()));
+        assert_eq!((stamp.len, stamp.hash), (14, content_hash(b"line 1\nline 2\n")));
+
+        fs::write(&path, b"rotated\n").unwrap();
+        assert_eq!(read_appended(&path, &mut stamp).unwrap(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn backup_previous_rotates_generations_next_to_the_file() {
+        let dir = test_dir("backup-sibling");
+        let path = dir.join("notes.txt");
+        let config = BackupConfig {
+            location: BackupLocation::Sibling,
+            generations: 2,
+            strict: false,
+        };
+        assert_eq!(backup_previous(&path, &config).unwrap(), None);
+        for version in ["v1", "v2", "v3"] {
+            fs::write(&path, version).unwrap();
+            let backup = backup_previous(&path, &config).unwrap();
+            assert_eq!(backup, Some(dir.join("notes.txt~")));
+        }
+        assert_eq!(fs::read(dir.join("notes.txt~")).unwrap(), b"v3");
+        assert_eq!(fs::read(dir.join("notes.txt~2")).unwrap(), b"v2");
+        assert_eq!(entries(&dir), vec!["notes.txt", "notes.txt~", "notes.txt~2"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn backup_previous_keeps_same_named_files_apart_in_a_directory() {
+        let dir = test_dir("backup-dir");
+        let backups = dir.join("backups");
+        let config = BackupConfig {
+            location: BackupLocation::Directory(backups.clone()),
+            generations: 1,
+            strict: false,
+        };
+        for sub in ["a", "b"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+            fs::write(dir.join(sub).join("notes.txt"), sub).unwrap();
+            backup_previous(&dir.join(sub).join("notes.txt"), &config).unwrap();
+        }
+        let names = entries(&backups);
+        assert_eq!(names.len(), 2);
+        assert!(names.iter().all(|name| name.starts_with("notes.txt.") && name.ends_with('~')));
+
+        fs::write(dir.join("backups-file"), b"").unwrap();
+        let blocked = BackupConfig {
+            location: BackupLocation::Directory(dir.join("backups-file")),
+            ..config
+        };
+        let err = backup_previous(&dir.join("a").join("notes.txt"), &blocked).unwrap_err();
+        assert!(err.describe().contains("create backup directory"), "{}", err.describe());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn save_atomic_keeps_permissions_and_symlinks() {
+        use std::os::unix::fs::{PermissionsExt, symlink};
+
+        let dir = test_dir("symlink");
+        let target = dir.join("script.sh");
+        fs::write(&target, b"old").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o750)).unwrap();
+        let link = dir.join("link.sh");
+        symlink("script.sh", &link).unwrap();
+
+        save_atomic(&link, b"new").unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read(&target).unwrap(), b"new");
+        let mode = fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o750);
+        assert_eq!(entries(&dir), vec!["link.sh", "script.sh"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
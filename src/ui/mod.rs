@@ -1,10 +1,12 @@
 use glyphon::{
     Attrs, Buffer, Color, Family, FontSystem, Metrics, Resolution, Shaping, SwashCache,
-    TextArea, TextAtlas, TextBounds, TextRenderer,
+    TextArea, TextAtlas, TextBounds, TextRenderer, Wrap,
 };
-use wgpu::util::DeviceExt;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthChar;
 use wgpu::SurfaceError;
 use winit::dpi::{PhysicalPosition, PhysicalSize};
+use winit::event::MouseScrollDelta;
 use winit::window::Window;
 
 pub struct Ui {
@@ -16,6 +18,8 @@ pub struct Ui {
     font_system: FontSystem,
     text_atlas: TextAtlas,
     text_renderer: TextRenderer,
+    /// Draws the picker after the caret so it covers the document.
+    overlay_renderer: TextRenderer,
     cache: SwashCache,
     tab_buffer: Buffer,
     line_number_buffer: Buffer,
@@ -24,10 +28,44 @@ pub struct Ui {
     line_number_digits: usize,
     caret_line: usize,
     caret_col: usize,
-    caret_pipeline: wgpu::RenderPipeline,
-    caret_vertex_buffer: wgpu::Buffer,
-    caret_uniform_buffer: wgpu::Buffer,
-    caret_bind_group: wgpu::BindGroup,
+    selection_spans: Vec<SelectionSpan>,
+    /// Runs flagged as encoding problems, drawn under the selection.
+    issue_spans: Vec<SelectionSpan>,
+    focused: bool,
+    tab_width: usize,
+    /// Per buffer line, the `(char col, spaces)` of each tab that was expanded
+    /// to spaces before layout.
+    tab_expansions: Vec<Vec<(usize, usize)>>,
+    scroll_line: usize,
+    scroll_remainder: f32,
+    picker: Option<PickerView>,
+    /// First item shown in the picker list.
+    picker_first: usize,
+    picker_buffer: Buffer,
+    quad_pipeline: wgpu::RenderPipeline,
+    quad_vertex_buffer: wgpu::Buffer,
+    quad_vertex_capacity: usize,
+    quad_uniform_buffer: wgpu::Buffer,
+    quad_bind_group: wgpu::BindGroup,
+}
+
+/// A highlighted run on a single line, in char columns. `line_break` adds
+/// one cell past `end_col` to show that the line break is selected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectionSpan {
+    pub line: usize,
+    pub start_col: usize,
+    pub end_col: usize,
+    pub line_break: bool,
+}
+
+/// A filterable list shown over the document, e.g. the encoding picker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PickerView {
+    /// Shown above the items; typically the prompt plus the current filter.
+    pub title: String,
+    pub items: Vec<String>,
+    pub selected: usize,
 }
 
 const FONT_SIZE: f32 = 18.0;
@@ -40,6 +78,17 @@ const CHAR_WIDTH_FACTOR: f32 = 0.6;
 const TAB_FONT_SIZE: f32 = 14.0;
 const TAB_LINE_HEIGHT: f32 = 20.0;
 const TAB_BAR_HEIGHT: f32 = 28.0;
+const WHEEL_LINES_PER_NOTCH: f32 = 3.0;
+const CARET_COLOR: [f32; 4] = [0.95, 0.95, 0.95, 1.0];
+const SELECTION_COLOR: [f32; 4] = [0.2, 0.36, 0.6, 1.0];
+const SELECTION_UNFOCUSED_COLOR: [f32; 4] = [0.28, 0.3, 0.33, 1.0];
+const ISSUE_COLOR: [f32; 4] = [0.55, 0.16, 0.16, 1.0];
+const PICKER_WIDTH: f32 = 420.0;
+const PICKER_PADDING: f32 = 8.0;
+const PICKER_ROWS: usize = 12;
+const PICKER_COLOR: [f32; 4] = [0.16, 0.17, 0.19, 1.0];
+const PICKER_SELECTED_COLOR: [f32; 4] = [0.2, 0.36, 0.6, 1.0];
+const INITIAL_QUAD_CAPACITY: usize = 64 * 6;
 
 impl Ui {
     pub async fn new(window: &Window) -> Self {
@@ -105,6 +154,8 @@ impl Ui {
         let mut text_atlas = TextAtlas::new(&device, &queue, config.format);
         let text_renderer =
             TextRenderer::new(&mut text_atlas, &device, wgpu::MultisampleState::default(), None);
+        let overlay_renderer =
+            TextRenderer::new(&mut text_atlas, &device, wgpu::MultisampleState::default(), None);
 
         let mut tab_buffer = Buffer::new(&mut font_system, Metrics::new(TAB_FONT_SIZE, TAB_LINE_HEIGHT));
         tab_buffer.set_size(
@@ -122,7 +173,12 @@ impl Ui {
         let line_number_digits = 1;
         let line_number_width = line_number_width_for_digits(line_number_digits);
         let mut line_number_buffer = Buffer::new(&mut font_system, Metrics::new(FONT_SIZE, LINE_HEIGHT));
-        line_number_buffer.set_size(&mut font_system, line_number_width, size.height as f32);
+        line_number_buffer.set_wrap(&mut font_system, Wrap::None);
+        line_number_buffer.set_size(
+            &mut font_system,
+            line_number_width,
+            text_buffer_height(size.height),
+        );
         line_number_buffer.set_text(
             &mut font_system,
             "",
@@ -131,8 +187,9 @@ impl Ui {
         );
 
         let mut buffer = Buffer::new(&mut font_system, Metrics::new(FONT_SIZE, LINE_HEIGHT));
+        buffer.set_wrap(&mut font_system, Wrap::None);
         let text_width = (size.width as f32 - (PADDING_X + line_number_width)).max(1.0);
-        buffer.set_size(&mut font_system, text_width, size.height as f32);
+        buffer.set_size(&mut font_system, text_width, text_buffer_height(size.height));
         buffer.set_text(
             &mut font_system,
             "",
@@ -140,23 +197,32 @@ impl Ui {
             Shaping::Advanced,
         );
 
-        let caret_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-            label: Some("caret shader"),
+        let mut picker_buffer = Buffer::new(&mut font_system, Metrics::new(FONT_SIZE, LINE_HEIGHT));
+        picker_buffer.set_wrap(&mut font_system, Wrap::None);
+        picker_buffer.set_size(
+            &mut font_system,
+            PICKER_WIDTH - 2.0 * PICKER_PADDING,
+            (PICKER_ROWS + 1) as f32 * LINE_HEIGHT,
+        );
+
+        let quad_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("quad shader"),
             source: wgpu::ShaderSource::Wgsl(
                 r#"
 struct Uniforms {
     screen_size: vec2<f32>,
-    color: vec4<f32>,
 }
 
 @group(0) @binding(0) var<uniform> uniforms: Uniforms;
 
 struct VertexInput {
     @location(0) position: vec2<f32>,
+    @location(1) color: vec4<f32>,
 }
 
 struct VertexOutput {
     @builtin(position) position: vec4<f32>,
+    @location(0) color: vec4<f32>,
 }
 
 @vertex
@@ -164,28 +230,29 @@ fn vs_main(input: VertexInput) -> VertexOutput {
     let normalized = (input.position / uniforms.screen_size) * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0);
     var out: VertexOutput;
     out.position = vec4<f32>(normalized, 0.0, 1.0);
+    out.color = input.color;
     return out;
 }
 
 @fragment
-fn fs_main() -> @location(0) vec4<f32> {
-    return uniforms.color;
+fn fs_main(input: VertexOutput) -> @location(0) vec4<f32> {
+    return input.color;
 }
 "#
                 .into(),
             ),
         });
 
-        let caret_uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("caret uniforms"),
-            size: std::mem::size_of::<CaretUniforms>() as u64,
+        let quad_uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("quad uniforms"),
+            size: std::mem::size_of::<QuadUniforms>() as u64,
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             mapped_at_creation: false,
         });
 
-        let caret_bind_group_layout =
+        let quad_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: Some("caret bind group layout"),
+                label: Some("quad bind group layout"),
                 entries: &[wgpu::BindGroupLayoutEntry {
                     binding: 0,
                     visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
@@ -193,46 +260,53 @@ fn fs_main() -> @location(0) vec4<f32> {
                         ty: wgpu::BufferBindingType::Uniform,
                         has_dynamic_offset: false,
                         min_binding_size: wgpu::BufferSize::new(
-                            std::mem::size_of::<CaretUniforms>() as u64,
+                            std::mem::size_of::<QuadUniforms>() as u64,
                         ),
                     },
                     count: None,
                 }],
             });
 
-        let caret_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("caret bind group"),
-            layout: &caret_bind_group_layout,
+        let quad_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("quad bind group"),
+            layout: &quad_bind_group_layout,
             entries: &[wgpu::BindGroupEntry {
                 binding: 0,
-                resource: caret_uniform_buffer.as_entire_binding(),
+                resource: quad_uniform_buffer.as_entire_binding(),
             }],
         });
 
-        let caret_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-            label: Some("caret pipeline layout"),
-            bind_group_layouts: &[&caret_bind_group_layout],
+        let quad_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("quad pipeline layout"),
+            bind_group_layouts: &[&quad_bind_group_layout],
             push_constant_ranges: &[],
         });
 
-        let caret_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("caret pipeline"),
-            layout: Some(&caret_pipeline_layout),
+        let quad_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("quad pipeline"),
+            layout: Some(&quad_pipeline_layout),
             vertex: wgpu::VertexState {
-                module: &caret_shader,
+                module: &quad_shader,
                 entry_point: "vs_main",
                 buffers: &[wgpu::VertexBufferLayout {
-                    array_stride: std::mem::size_of::<[f32; 2]>() as u64,
+                    array_stride: std::mem::size_of::<QuadVertex>() as u64,
                     step_mode: wgpu::VertexStepMode::Vertex,
-                    attributes: &[wgpu::VertexAttribute {
-                        format: wgpu::VertexFormat::Float32x2,
-                        offset: 0,
-                        shader_location: 0,
-                    }],
+                    attributes: &[
+                        wgpu::VertexAttribute {
+                            format: wgpu::VertexFormat::Float32x2,
+                            offset: 0,
+                            shader_location: 0,
+                        },
+                        wgpu::VertexAttribute {
+                            format: wgpu::VertexFormat::Float32x4,
+                            offset: std::mem::size_of::<[f32; 2]>() as u64,
+                            shader_location: 1,
+                        },
+                    ],
                 }],
             },
             fragment: Some(wgpu::FragmentState {
-                module: &caret_shader,
+                module: &quad_shader,
                 entry_point: "fs_main",
                 targets: &[Some(wgpu::ColorTargetState {
                     format: config.format,
@@ -246,11 +320,7 @@ fn fs_main() -> @location(0) vec4<f32> {
             multiview: None,
         });
 
-        let caret_vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("caret vertices"),
-            contents: bytemuck::cast_slice(&[0.0_f32; 12]),
-            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-        });
+        let quad_vertex_buffer = create_quad_vertex_buffer(&device, INITIAL_QUAD_CAPACITY);
 
         Self {
             surface,
@@ -261,6 +331,7 @@ fn fs_main() -> @location(0) vec4<f32> {
             font_system,
             text_atlas,
             text_renderer,
+            overlay_renderer,
             cache,
             tab_buffer,
             line_number_buffer,
@@ -269,10 +340,21 @@ fn fs_main() -> @location(0) vec4<f32> {
             line_number_digits,
             caret_line: 0,
             caret_col: 0,
-            caret_pipeline,
-            caret_vertex_buffer,
-            caret_uniform_buffer,
-            caret_bind_group,
+            selection_spans: Vec::new(),
+            issue_spans: Vec::new(),
+            focused: true,
+            tab_width: 4,
+            tab_expansions: Vec::new(),
+            scroll_line: 0,
+            scroll_remainder: 0.0,
+            picker: None,
+            picker_first: 0,
+            picker_buffer,
+            quad_pipeline,
+            quad_vertex_buffer,
+            quad_vertex_capacity: INITIAL_QUAD_CAPACITY,
+            quad_uniform_buffer,
+            quad_bind_group,
         }
     }
 
@@ -296,23 +378,42 @@ fn fs_main() -> @location(0) vec4<f32> {
         self.line_number_buffer.set_size(
             &mut self.font_system,
             self.line_number_width.max(1.0),
-            new_size.height as f32,
+            text_buffer_height(new_size.height),
         );
         self.buffer
             .set_size(
                 &mut self.font_system,
                 (new_size.width as f32 - (PADDING_X + self.line_number_width)).max(1.0),
-                new_size.height as f32,
+                text_buffer_height(new_size.height),
             );
+        self.apply_scroll();
+    }
+
+    /// Sets the tab stop width used by the next `set_text`.
+    pub fn set_tab_width(&mut self, tab_width: usize) {
+        self.tab_width = tab_width.max(1);
     }
 
     pub fn set_text(&mut self, text: &str) {
+        // The shaper has no notion of tab stops, so tabs are laid out as the
+        // spaces that reach the next stop.
+        self.tab_expansions.clear();
+        let mut expanded = String::with_capacity(text.len());
+        for (idx, line) in text.split('\n').enumerate() {
+            if idx > 0 {
+                expanded.push('\n');
+            }
+            let (line, tabs) = expand_tabs(line, self.tab_width);
+            expanded.push_str(&line);
+            self.tab_expansions.push(tabs);
+        }
         self.buffer.set_text(
             &mut self.font_system,
-            text,
+            &expanded,
             Attrs::new().family(Family::Monospace),
             Shaping::Advanced,
         );
+        self.apply_scroll();
     }
 
     pub fn set_line_numbers(&mut self, text: &str, digits: usize) {
@@ -324,10 +425,13 @@ fn fs_main() -> @location(0) vec4<f32> {
             self.line_number_buffer.set_size(
                 &mut self.font_system,
                 self.line_number_width.max(1.0),
-                self.size.height as f32,
+                text_buffer_height(self.size.height),
+            );
+            self.buffer.set_size(
+                &mut self.font_system,
+                text_width,
+                text_buffer_height(self.size.height),
             );
-            self.buffer
-                .set_size(&mut self.font_system, text_width, self.size.height as f32);
         }
         self.line_number_buffer.set_text(
             &mut self.font_system,
@@ -335,6 +439,7 @@ fn fs_main() -> @location(0) vec4<f32> {
             Attrs::new().family(Family::Monospace),
             Shaping::Advanced,
         );
+        self.apply_scroll();
     }
 
     pub fn set_caret(&mut self, line: usize, col: usize) {
@@ -342,6 +447,45 @@ fn fs_main() -> @location(0) vec4<f32> {
         self.caret_col = col;
     }
 
+    pub fn set_selection(&mut self, spans: Vec<SelectionSpan>) {
+        self.selection_spans = spans;
+    }
+
+    pub fn set_issues(&mut self, spans: Vec<SelectionSpan>) {
+        self.issue_spans = spans;
+    }
+
+    /// Shows `picker` over the document, or hides it when `None`.
+    pub fn set_picker(&mut self, picker: Option<PickerView>) {
+        if let Some(view) = &picker {
+            let len = view.items.len();
+            let rows = picker_window(self.picker_first, view.selected, len, PICKER_ROWS);
+            self.picker_first = rows.start;
+            let mut text = view.title.clone();
+            for item in &view.items[rows] {
+                text.push('\n');
+                text.push_str(item);
+            }
+            self.picker_buffer.set_text(
+                &mut self.font_system,
+                &text,
+                Attrs::new().family(Family::Monospace),
+                Shaping::Advanced,
+            );
+        } else {
+            self.picker_first = 0;
+        }
+        self.picker = picker;
+    }
+
+    pub fn set_focused(&mut self, focused: bool) {
+        self.focused = focused;
+    }
+
+    pub fn scroll_line(&self) -> usize {
+        self.scroll_line
+    }
+
     pub fn set_tabs(&mut self, text: &str) {
         self.tab_buffer.set_text(
             &mut self.font_system,
@@ -351,10 +495,89 @@ fn fs_main() -> @location(0) vec4<f32> {
         );
     }
 
+    /// Number of text lines that fit fully below the tab bar.
+    pub fn visible_line_count(&self) -> usize {
+        visible_lines_for_height(self.size.height)
+    }
+
+    /// Scrolls the viewport just enough to make `line` fully visible.
+    pub fn scroll_to_line(&mut self, line: usize, line_count: usize) {
+        let first = scroll_to_include(self.scroll_line, line, self.visible_line_count());
+        self.set_scroll_line(first, line_count);
+    }
+
+    /// Applies a mouse-wheel or trackpad delta. Returns true when the first
+    /// visible line changed.
+    pub fn scroll_by(&mut self, delta: MouseScrollDelta, line_count: usize) -> bool {
+        let lines = match delta {
+            MouseScrollDelta::LineDelta(_, y) => -y * WHEEL_LINES_PER_NOTCH,
+            MouseScrollDelta::PixelDelta(position) => -(position.y as f32) / LINE_HEIGHT,
+        };
+        self.scroll_remainder += lines;
+        let whole = self.scroll_remainder.trunc();
+        self.scroll_remainder -= whole;
+        if whole == 0.0 {
+            return false;
+        }
+        let target = (self.scroll_line as i64 + whole as i64).max(0) as usize;
+        let before = self.scroll_line;
+        self.set_scroll_line(target, line_count);
+        if self.scroll_line == before {
+            self.scroll_remainder = 0.0;
+        }
+        self.scroll_line != before
+    }
+
+    fn set_scroll_line(&mut self, line: usize, line_count: usize) {
+        self.scroll_line = clamp_scroll_line(line, line_count, self.visible_line_count());
+        self.apply_scroll();
+    }
+
+    fn apply_scroll(&mut self) {
+        let scroll = self.scroll_line as i32;
+        self.buffer.set_scroll(scroll);
+        self.buffer.shape_until_scroll(&mut self.font_system);
+        self.line_number_buffer.set_scroll(scroll);
+        self.line_number_buffer.shape_until_scroll(&mut self.font_system);
+    }
+
+    /// Caret cell for (line, char col), measured from the shaped layout so it
+    /// lines up with wide and proportional glyphs.
     pub fn caret_rect(&self, line: usize, col: usize) -> (f64, f64, f64, f64) {
-        let char_width = FONT_SIZE * CHAR_WIDTH_FACTOR;
-        let (x, y) = caret_origin(line, col, self.line_number_width);
-        (x as f64, y as f64, char_width as f64, LINE_HEIGHT as f64)
+        let advances = self.line_advances(line);
+        let offset = x_for_col(&advances, col);
+        let width = advances
+            .get(col)
+            .copied()
+            .filter(|width| *width > 0.0)
+            .unwrap_or(FONT_SIZE * CHAR_WIDTH_FACTOR);
+        let (x, y) = caret_origin(line, offset, self.line_number_width, self.scroll_line);
+        (x as f64, y as f64, width as f64, LINE_HEIGHT as f64)
+    }
+
+    /// Per-char advances of a buffer line, taken from its layout when it has
+    /// been shaped and from East Asian widths otherwise.
+    fn line_advances(&self, line: usize) -> Vec<f32> {
+        let Some(buffer_line) = self.buffer.lines.get(line) else {
+            return Vec::new();
+        };
+        let glyphs = buffer_line
+            .layout_opt()
+            .as_ref()
+            .and_then(|layout| layout.first())
+            .map(|layout_line| {
+                layout_line
+                    .glyphs
+                    .iter()
+                    .map(|glyph| (glyph.start, glyph.end, glyph.w))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let advances = char_advances(buffer_line.text(), &glyphs);
+        match self.tab_expansions.get(line) {
+            Some(tabs) if !tabs.is_empty() => collapse_tab_advances(&advances, tabs),
+            _ => advances,
+        }
     }
 
     pub fn line_number_hit_test(
@@ -373,13 +596,54 @@ fn fs_main() -> @location(0) vec4<f32> {
         if y < top || y > self.size.height as f32 {
             return None;
         }
-        let line = ((y - top) / LINE_HEIGHT).floor() as usize;
+        let line = self.scroll_line + ((y - top) / LINE_HEIGHT).floor() as usize;
         if line >= line_count.max(1) {
             return None;
         }
         Some(line)
     }
 
+    /// Maps a click inside the text area to a (line, char column) pair.
+    pub fn text_hit_test(
+        &self,
+        position: PhysicalPosition<f64>,
+        line_count: usize,
+    ) -> Option<(usize, usize)> {
+        let x = position.x as f32;
+        let y = position.y as f32;
+        let text_left = PADDING_X + self.line_number_width;
+        let top = PADDING_Y + TAB_BAR_HEIGHT;
+        if x < text_left || x > self.size.width as f32 {
+            return None;
+        }
+        if y < top || y > self.size.height as f32 {
+            return None;
+        }
+        Some(self.text_position(x, y, line_count))
+    }
+
+    /// Like `text_hit_test`, but clamps positions outside the text area so a
+    /// drag past the edges keeps extending the selection.
+    pub fn text_drag_position(
+        &self,
+        position: PhysicalPosition<f64>,
+        line_count: usize,
+    ) -> (usize, usize) {
+        self.text_position(position.x as f32, position.y as f32, line_count)
+    }
+
+    fn text_position(&self, x: f32, y: f32, line_count: usize) -> (usize, usize) {
+        let top = PADDING_Y + TAB_BAR_HEIGHT;
+        let max_line = line_count.max(1) - 1;
+        let line = if y < top {
+            self.scroll_line.saturating_sub(1)
+        } else {
+            (self.scroll_line + ((y - top) / LINE_HEIGHT).floor() as usize).min(max_line)
+        };
+        let local_x = x - (PADDING_X + self.line_number_width);
+        (line, col_for_x(&self.line_advances(line), local_x))
+    }
+
     pub fn render(&mut self) -> Result<(), SurfaceError> {
         let output = self.surface.get_current_texture()?;
         let view = output
@@ -443,6 +707,106 @@ fn fs_main() -> @location(0) vec4<f32> {
                 &mut self.cache,
             )
             .expect("prepare text");
+        let picker_rect = self
+            .picker
+            .as_ref()
+            .map(|view| picker_rect_pixels(self.size.width, view.items.len().min(PICKER_ROWS)));
+        if let Some((x, y, _, h)) = picker_rect {
+            self.overlay_renderer
+                .prepare(
+                    &self.device,
+                    &self.queue,
+                    &mut self.font_system,
+                    &mut self.text_atlas,
+                    Resolution {
+                        width: self.size.width,
+                        height: self.size.height,
+                    },
+                    [TextArea {
+                        buffer: &self.picker_buffer,
+                        left: x + PICKER_PADDING,
+                        top: y + PICKER_PADDING,
+                        scale: 1.0,
+                        bounds: TextBounds {
+                            left: x as i32,
+                            top: y as i32,
+                            right: (x + PICKER_WIDTH - PICKER_PADDING) as i32,
+                            bottom: (y + h) as i32,
+                        },
+                        default_color: Color::rgb(230, 230, 230),
+                    }],
+                    &mut self.cache,
+                )
+                .expect("prepare picker text");
+        }
+
+        let selection_color = if self.focused {
+            SELECTION_COLOR
+        } else {
+            SELECTION_UNFOCUSED_COLOR
+        };
+        let visible_end = self.scroll_line + self.visible_line_count();
+        let issues = self.issue_spans.iter().map(|span| (span, ISSUE_COLOR));
+        let selection = self.selection_spans.iter().map(|span| (span, selection_color));
+        let mut vertices: Vec<QuadVertex> = issues
+            .chain(selection)
+            .filter(|(span, _)| span.line >= self.scroll_line && span.line <= visible_end)
+            .flat_map(|(span, color)| {
+                let advances = self.line_advances(span.line);
+                let rect = selection_rect_pixels(
+                    *span,
+                    &advances,
+                    self.line_number_width,
+                    self.scroll_line,
+                );
+                quad_vertices(rect, color)
+            })
+            .collect();
+        let selection_vertex_count = vertices.len() as u32;
+        let caret_visible = self.focused
+            && self.caret_line >= self.scroll_line
+            && self.caret_line <= visible_end;
+        if caret_visible {
+            let offset = x_for_col(&self.line_advances(self.caret_line), self.caret_col);
+            let caret_rect = caret_rect_pixels(
+                self.caret_line,
+                offset,
+                self.line_number_width,
+                self.scroll_line,
+            );
+            vertices.extend_from_slice(&quad_vertices(caret_rect, CARET_COLOR));
+        }
+        let picker_vertex_start = vertices.len() as u32;
+        if let (Some(rect), Some(view)) = (picker_rect, &self.picker) {
+            vertices.extend_from_slice(&quad_vertices(rect, PICKER_COLOR));
+            if view.selected < view.items.len() {
+                let row = view.selected - self.picker_first;
+                let row_rect = picker_row_rect(rect, row);
+                vertices.extend_from_slice(&quad_vertices(row_rect, PICKER_SELECTED_COLOR));
+            }
+        }
+        let picker_vertex_end = vertices.len() as u32;
+        if vertices.len() > self.quad_vertex_capacity {
+            self.quad_vertex_capacity = vertices.len().next_power_of_two();
+            self.quad_vertex_buffer =
+                create_quad_vertex_buffer(&self.device, self.quad_vertex_capacity);
+        }
+        if !vertices.is_empty() {
+            self.queue.write_buffer(
+                &self.quad_vertex_buffer,
+                0,
+                bytemuck::cast_slice(&vertices),
+            );
+        }
+        let uniforms = QuadUniforms {
+            screen_size: [self.size.width as f32, self.size.height as f32],
+            _padding: [0.0, 0.0],
+        };
+        self.queue.write_buffer(
+            &self.quad_uniform_buffer,
+            0,
+            bytemuck::bytes_of(&uniforms),
+        );
 
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
@@ -465,48 +829,34 @@ fn fs_main() -> @location(0) vec4<f32> {
                 occlusion_query_set: None,
             });
 
+            // Selection goes behind the glyphs, the caret on top of them.
+            render_pass.set_pipeline(&self.quad_pipeline);
+            render_pass.set_bind_group(0, &self.quad_bind_group, &[]);
+            render_pass.set_vertex_buffer(0, self.quad_vertex_buffer.slice(..));
+            if selection_vertex_count > 0 {
+                render_pass.draw(0..selection_vertex_count, 0..1);
+            }
+
             self.text_renderer
                 .render(&self.text_atlas, &mut render_pass)
                 .expect("render text");
-        }
 
-        let caret_rect =
-            caret_rect_pixels(self.caret_line, self.caret_col, self.line_number_width);
-        let vertices = caret_vertices(caret_rect);
-        self.queue.write_buffer(
-            &self.caret_vertex_buffer,
-            0,
-            bytemuck::cast_slice(&vertices),
-        );
-        let uniforms = CaretUniforms {
-            screen_size: [self.size.width as f32, self.size.height as f32],
-            _padding: [0.0, 0.0],
-            color: [0.95, 0.95, 0.95, 1.0],
-        };
-        self.queue.write_buffer(
-            &self.caret_uniform_buffer,
-            0,
-            bytemuck::bytes_of(&uniforms),
-        );
-        {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("caret pass"),
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Load,
-                        store: wgpu::StoreOp::Store,
-                    },
-                })],
-                depth_stencil_attachment: None,
-                timestamp_writes: None,
-                occlusion_query_set: None,
-            });
-            render_pass.set_pipeline(&self.caret_pipeline);
-            render_pass.set_bind_group(0, &self.caret_bind_group, &[]);
-            render_pass.set_vertex_buffer(0, self.caret_vertex_buffer.slice(..));
-            render_pass.draw(0..6, 0..1);
+            if caret_visible {
+                render_pass.set_pipeline(&self.quad_pipeline);
+                render_pass.set_bind_group(0, &self.quad_bind_group, &[]);
+                render_pass.set_vertex_buffer(0, self.quad_vertex_buffer.slice(..));
+                render_pass.draw(selection_vertex_count..selection_vertex_count + 6, 0..1);
+            }
+
+            if picker_vertex_end > picker_vertex_start {
+                render_pass.set_pipeline(&self.quad_pipeline);
+                render_pass.set_bind_group(0, &self.quad_bind_group, &[]);
+                render_pass.set_vertex_buffer(0, self.quad_vertex_buffer.slice(..));
+                render_pass.draw(picker_vertex_start..picker_vertex_end, 0..1);
+                self.overlay_renderer
+                    .render(&self.text_atlas, &mut render_pass)
+                    .expect("render picker text");
+            }
         }
 
         self.queue.submit(Some(encoder.finish()));
@@ -517,47 +867,223 @@ fn fs_main() -> @location(0) vec4<f32> {
 
 #[repr(C, align(16))]
 #[derive(Copy, Clone)]
-struct CaretUniforms {
+struct QuadUniforms {
     screen_size: [f32; 2],
     _padding: [f32; 2],
+}
+
+unsafe impl bytemuck::Pod for QuadUniforms {}
+unsafe impl bytemuck::Zeroable for QuadUniforms {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+struct QuadVertex {
+    position: [f32; 2],
     color: [f32; 4],
 }
 
-unsafe impl bytemuck::Pod for CaretUniforms {}
-unsafe impl bytemuck::Zeroable for CaretUniforms {}
+fn create_quad_vertex_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("quad vertices"),
+        size: (capacity * std::mem::size_of::<QuadVertex>()) as u64,
+        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
 
 fn line_number_width_for_digits(digits: usize) -> f32 {
     let char_width = FONT_SIZE * CHAR_WIDTH_FACTOR;
     (digits as f32 * char_width) + GUTTER_PADDING_LEFT + GUTTER_PADDING_RIGHT
 }
 
-fn caret_origin(line: usize, col: usize, line_number_width: f32) -> (f32, f32) {
+/// Per-char advances for a laid out line. Chars that share a glyph cluster
+/// split its width evenly; chars without a glyph fall back to their East
+/// Asian width in nominal monospace cells.
+fn char_advances(text: &str, glyphs: &[(usize, usize, f32)]) -> Vec<f32> {
+    let char_width = FONT_SIZE * CHAR_WIDTH_FACTOR;
+    let starts: Vec<usize> = text.char_indices().map(|(idx, _)| idx).collect();
+    let mut advances: Vec<f32> = text
+        .chars()
+        .map(|ch| ch.width().unwrap_or(0) as f32 * char_width)
+        .collect();
+    for &(start, end, w) in glyphs {
+        let first = starts.partition_point(|&idx| idx < start);
+        let last = starts.partition_point(|&idx| idx < end);
+        if last > first {
+            let share = w / (last - first) as f32;
+            for advance in &mut advances[first..last] {
+                *advance = share;
+            }
+        }
+    }
+    advances
+}
+
+/// Replaces each tab in `line` with spaces up to the next tab stop, measuring
+/// columns the same way as `Core::display_col`. Returns the expanded line and
+/// the `(char col, spaces)` of every tab.
+fn expand_tabs(line: &str, tab_width: usize) -> (String, Vec<(usize, usize)>) {
+    let mut expanded = String::with_capacity(line.len());
+    let mut tabs = Vec::new();
+    let mut width = 0;
+    let mut col = 0;
+    for grapheme in line.graphemes(true) {
+        let cells = crate::core::grapheme_display_width(grapheme, width, tab_width);
+        if grapheme == "\t" {
+            tabs.push((col, cells));
+            expanded.extend(std::iter::repeat_n(' ', cells));
+        } else {
+            expanded.push_str(grapheme);
+        }
+        width += cells;
+        col += grapheme.chars().count();
+    }
+    (expanded, tabs)
+}
+
+/// Folds the advances of an expanded line back to one advance per document
+/// char, giving each tab the summed width of its spaces.
+fn collapse_tab_advances(advances: &[f32], tabs: &[(usize, usize)]) -> Vec<f32> {
+    let mut collapsed = Vec::with_capacity(advances.len());
+    let mut tabs = tabs.iter().peekable();
+    let mut idx = 0;
+    while idx < advances.len() {
+        let span = match tabs.peek() {
+            Some(&&(col, spaces)) if col == collapsed.len() => {
+                tabs.next();
+                spaces.max(1)
+            }
+            _ => 1,
+        };
+        let end = (idx + span).min(advances.len());
+        collapsed.push(advances[idx..end].iter().sum());
+        idx = end;
+    }
+    collapsed
+}
+
+/// Returns the char column whose boundary is nearest to `x`.
+fn col_for_x(advances: &[f32], x: f32) -> usize {
+    let mut left = 0.0;
+    for (col, width) in advances.iter().enumerate() {
+        if x < left + width / 2.0 {
+            return col;
+        }
+        left += width;
+    }
+    advances.len()
+}
+
+/// Horizontal offset of the left edge of char column `col`. Columns past
+/// the end of the line continue in nominal cells.
+fn x_for_col(advances: &[f32], col: usize) -> f32 {
     let char_width = FONT_SIZE * CHAR_WIDTH_FACTOR;
-    let x = PADDING_X + line_number_width + (col as f32 * char_width);
-    let y = PADDING_Y + TAB_BAR_HEIGHT + (line as f32 * LINE_HEIGHT);
+    let laid_out: f32 = advances.iter().take(col).sum();
+    laid_out + col.saturating_sub(advances.len()) as f32 * char_width
+}
+
+fn text_buffer_height(window_height: u32) -> f32 {
+    // One extra row so a partially visible last line still gets laid out.
+    (window_height as f32 - (PADDING_Y + TAB_BAR_HEIGHT)).max(0.0) + LINE_HEIGHT
+}
+
+fn visible_lines_for_height(window_height: u32) -> usize {
+    let viewport = (window_height as f32 - (PADDING_Y + TAB_BAR_HEIGHT)).max(0.0);
+    ((viewport / LINE_HEIGHT).floor() as usize).max(1)
+}
+
+fn clamp_scroll_line(first_line: usize, line_count: usize, visible_lines: usize) -> usize {
+    first_line.min(line_count.saturating_sub(visible_lines))
+}
+
+fn scroll_to_include(first_line: usize, line: usize, visible_lines: usize) -> usize {
+    let visible_lines = visible_lines.max(1);
+    if line < first_line {
+        line
+    } else if line >= first_line + visible_lines {
+        line + 1 - visible_lines
+    } else {
+        first_line
+    }
+}
+
+fn caret_origin(
+    line: usize,
+    offset: f32,
+    line_number_width: f32,
+    scroll_line: usize,
+) -> (f32, f32) {
+    let x = PADDING_X + line_number_width + offset;
+    let row = line as f32 - scroll_line as f32;
+    let y = PADDING_Y + TAB_BAR_HEIGHT + (row * LINE_HEIGHT);
     (x, y)
 }
 
-fn caret_rect_pixels(line: usize, col: usize, line_number_width: f32) -> (f32, f32, f32, f32) {
-    let (x, y) = caret_origin(line, col, line_number_width);
+fn caret_rect_pixels(
+    line: usize,
+    offset: f32,
+    line_number_width: f32,
+    scroll_line: usize,
+) -> (f32, f32, f32, f32) {
+    let (x, y) = caret_origin(line, offset, line_number_width, scroll_line);
     (x, y, 2.0, LINE_HEIGHT)
 }
 
-fn caret_vertices(rect: (f32, f32, f32, f32)) -> [f32; 12] {
+fn selection_rect_pixels(
+    span: SelectionSpan,
+    advances: &[f32],
+    line_number_width: f32,
+    scroll_line: usize,
+) -> (f32, f32, f32, f32) {
+    let start = x_for_col(advances, span.start_col);
+    let mut end = x_for_col(advances, span.end_col);
+    if span.line_break {
+        end += FONT_SIZE * CHAR_WIDTH_FACTOR;
+    }
+    let (x, y) = caret_origin(span.line, start, line_number_width, scroll_line);
+    (x, y, (end - start).max(0.0), LINE_HEIGHT)
+}
+
+/// The items shown for a picker: keeps `first` if `selected` is still on
+/// screen, otherwise scrolls just far enough to show it.
+fn picker_window(
+    first: usize,
+    selected: usize,
+    len: usize,
+    rows: usize,
+) -> std::ops::Range<usize> {
+    let first = scroll_to_include(first, selected.min(len.saturating_sub(1)), rows);
+    let first = clamp_scroll_line(first, len, rows);
+    first..(first + rows).min(len)
+}
+
+/// The picker panel: a title row plus `rows` items, centered horizontally
+/// just below the tab bar.
+fn picker_rect_pixels(window_width: u32, rows: usize) -> (f32, f32, f32, f32) {
+    let width = PICKER_WIDTH.min(window_width as f32);
+    let x = ((window_width as f32 - width) / 2.0).max(0.0);
+    let height = (rows + 1) as f32 * LINE_HEIGHT + 2.0 * PICKER_PADDING;
+    (x, PADDING_Y + TAB_BAR_HEIGHT, width, height)
+}
+
+fn picker_row_rect(panel: (f32, f32, f32, f32), row: usize) -> (f32, f32, f32, f32) {
+    let (x, y, w, _) = panel;
+    (x, y + PICKER_PADDING + (row + 1) as f32 * LINE_HEIGHT, w, LINE_HEIGHT)
+}
+
+fn quad_vertices(rect: (f32, f32, f32, f32), color: [f32; 4]) -> [QuadVertex; 6] {
     let (x, y, w, h) = rect;
+    let vertex = |x, y| QuadVertex {
+        position: [x, y],
+        color,
+    };
     [
-        x,
-        y,
-        x + w,
-        y,
-        x + w,
-        y + h,
-        x,
-        y,
-        x + w,
-        y + h,
-        x,
-        y + h,
+        vertex(x, y),
+        vertex(x + w, y),
+        vertex(x + w, y + h),
+        vertex(x, y),
+        vertex(x + w, y + h),
+        vertex(x, y + h),
     ]
 }
 
@@ -568,18 +1094,122 @@ mod tests {
     #[test]
     fn caret_origin_accounts_for_gutter_width() {
         let gutter = line_number_width_for_digits(3);
-        let (x, y) = caret_origin(0, 0, gutter);
+        let (x, y) = caret_origin(0, 0.0, gutter, 0);
         assert!((x - (PADDING_X + gutter)).abs() < f32::EPSILON);
         assert!((y - (PADDING_Y + TAB_BAR_HEIGHT)).abs() < f32::EPSILON);
     }
 
     #[test]
-    fn caret_vertices_builds_two_triangles() {
-        let vertices = caret_vertices((10.0, 20.0, 2.0, 5.0));
-        assert_eq!(vertices.len(), 12);
-        assert_eq!(vertices[0], 10.0);
-        assert_eq!(vertices[1], 20.0);
-        assert_eq!(vertices[10], 10.0);
-        assert_eq!(vertices[11], 25.0);
+    fn caret_origin_subtracts_scroll_offset() {
+        let gutter = line_number_width_for_digits(2);
+        let (_, y) = caret_origin(12, 0.0, gutter, 10);
+        assert!((y - (PADDING_Y + TAB_BAR_HEIGHT + 2.0 * LINE_HEIGHT)).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn scroll_to_include_moves_minimally() {
+        assert_eq!(scroll_to_include(10, 12, 5), 10);
+        assert_eq!(scroll_to_include(10, 3, 5), 3);
+        assert_eq!(scroll_to_include(10, 15, 5), 11);
+    }
+
+    #[test]
+    fn col_for_x_snaps_to_nearest_boundary() {
+        let cw = FONT_SIZE * CHAR_WIDTH_FACTOR;
+        let advances = char_advances("abc", &[]);
+        assert_eq!(col_for_x(&advances, -5.0), 0);
+        assert_eq!(col_for_x(&advances, cw * 0.4), 0);
+        assert_eq!(col_for_x(&advances, cw * 0.6), 1);
+        assert_eq!(col_for_x(&advances, cw * 10.0), 3);
+    }
+
+    #[test]
+    fn col_for_x_expands_tabs() {
+        let cw = FONT_SIZE * CHAR_WIDTH_FACTOR;
+        let (expanded, tabs) = expand_tabs("\tb", 4);
+        let advances = collapse_tab_advances(&char_advances(&expanded, &[]), &tabs);
+        assert_eq!(col_for_x(&advances, cw * 1.5), 0);
+        assert_eq!(col_for_x(&advances, cw * 2.5), 1);
+        assert_eq!(col_for_x(&advances, cw * 4.6), 2);
+    }
+
+    #[test]
+    fn expand_tabs_stops_at_tab_width_multiples() {
+        let (expanded, tabs) = expand_tabs("ab\tc\tあ\t", 4);
+        assert_eq!(expanded, "ab  c   あ  ");
+        assert_eq!(tabs, vec![(2, 2), (4, 3), (6, 2)]);
+        let (expanded, tabs) = expand_tabs("\t", 8);
+        assert_eq!(expanded, " ".repeat(8));
+        assert_eq!(tabs, vec![(0, 8)]);
+    }
+
+    #[test]
+    fn char_advances_uses_glyph_widths() {
+        // "あい" is two 3-byte chars; a ligature-like cluster covers both.
+        let advances = char_advances("aあい", &[(0, 1, 10.0), (1, 7, 36.0)]);
+        assert_eq!(advances, vec![10.0, 18.0, 18.0]);
+        assert_eq!(col_for_x(&advances, 18.0), 1);
+        assert_eq!(col_for_x(&advances, 20.0), 2);
+    }
+
+    #[test]
+    fn char_advances_falls_back_to_east_asian_width() {
+        let cw = FONT_SIZE * CHAR_WIDTH_FACTOR;
+        let advances = char_advances("aあ\u{3099}", &[]);
+        assert_eq!(advances, vec![cw, 2.0 * cw, 0.0]);
+        assert!((x_for_col(&advances, 2) - 3.0 * cw).abs() < 1e-4);
+        assert!((x_for_col(&advances, 5) - 5.0 * cw).abs() < 1e-4);
+    }
+
+    #[test]
+    fn picker_window_follows_selection() {
+        assert_eq!(picker_window(0, 3, 40, 12), 0..12);
+        assert_eq!(picker_window(0, 15, 40, 12), 4..16);
+        assert_eq!(picker_window(10, 12, 40, 12), 10..22);
+        assert_eq!(picker_window(30, 0, 5, 12), 0..5);
+        assert_eq!(picker_window(0, 0, 0, 12), 0..0);
+    }
+
+    #[test]
+    fn picker_rows_sit_below_the_title() {
+        let panel = picker_rect_pixels(1000, 4);
+        assert_eq!(panel.0, (1000.0 - PICKER_WIDTH) / 2.0);
+        assert_eq!(panel.3, 5.0 * LINE_HEIGHT + 2.0 * PICKER_PADDING);
+        let row = picker_row_rect(panel, 0);
+        assert_eq!(row.1, panel.1 + PICKER_PADDING + LINE_HEIGHT);
+        assert_eq!(picker_rect_pixels(200, 1).2, 200.0);
+    }
+
+    #[test]
+    fn clamp_scroll_line_keeps_last_page_full() {
+        assert_eq!(clamp_scroll_line(50, 40, 10), 30);
+        assert_eq!(clamp_scroll_line(5, 3, 10), 0);
+    }
+
+    #[test]
+    fn quad_vertices_builds_two_triangles() {
+        let vertices = quad_vertices((10.0, 20.0, 2.0, 5.0), CARET_COLOR);
+        assert_eq!(vertices.len(), 6);
+        assert_eq!(vertices[0].position, [10.0, 20.0]);
+        assert_eq!(vertices[5].position, [10.0, 25.0]);
+        assert!(vertices.iter().all(|vertex| vertex.color == CARET_COLOR));
+    }
+
+    #[test]
+    fn selection_rect_follows_glyph_advances() {
+        let gutter = line_number_width_for_digits(1);
+        let char_width = FONT_SIZE * CHAR_WIDTH_FACTOR;
+        let advances = char_advances("abあいう", &[]);
+        let span = SelectionSpan {
+            line: 3,
+            start_col: 2,
+            end_col: 4,
+            line_break: true,
+        };
+        let (x, y, w, h) = selection_rect_pixels(span, &advances, gutter, 1);
+        assert!((x - (PADDING_X + gutter + 2.0 * char_width)).abs() < 1e-4);
+        assert!((y - (PADDING_Y + TAB_BAR_HEIGHT + 2.0 * LINE_HEIGHT)).abs() < 1e-4);
+        assert!((w - 5.0 * char_width).abs() < 1e-4);
+        assert!((h - LINE_HEIGHT).abs() < f32::EPSILON);
     }
 }